@@ -5,6 +5,37 @@ import os
 import json
 import traceback
 
+def run_mcp_session(binary_path, request):
+    """Run a fresh server, complete the MCP handshake, then send `request`.
+
+    Returns the completed process with stdout narrowed to the response for `request`.
+    """
+    messages = [
+        {
+            "jsonrpc": "2.0",
+            "id": 0,
+            "method": "initialize",
+            "params": {"protocolVersion": "2025-06-18", "capabilities": {}}
+        },
+        {"jsonrpc": "2.0", "method": "notifications/initialized"},
+        request,
+    ]
+    result = subprocess.run(
+        [binary_path],
+        input="".join(json.dumps(message) + "\n" for message in messages),
+        capture_output=True,
+        text=True,
+    )
+    for line in result.stdout.splitlines():
+        try:
+            response = json.loads(line)
+        except json.JSONDecodeError:
+            continue
+        if response.get("id") == request.get("id"):
+            result.stdout = line
+            break
+    return result
+
 def run_mcp_tests():
     """Run the MCP server tests"""
     try:
@@ -71,7 +102,7 @@ def run_mcp_tests():
             "id": 1,
             "method": "tools/list"
         }
-        result = subprocess.run(f"echo '{json.dumps(list_cmd)}' | {binary_path}", shell=True, capture_output=True, text=True)
+        result = run_mcp_session(binary_path, list_cmd)
         try:
             response = json.loads(result.stdout)
             if "result" not in response or "tools" not in response["result"]:
@@ -100,7 +131,7 @@ def run_mcp_tests():
                 "arguments": {}
             }
         }
-        result = subprocess.run(f"echo '{json.dumps(check_cmd)}' | {binary_path}", shell=True, capture_output=True, text=True)
+        result = run_mcp_session(binary_path, check_cmd)
         try:
             response = json.loads(result.stdout)
             if "result" not in response or "content" not in response["result"]:
@@ -131,7 +162,7 @@ def run_mcp_tests():
                 }
             }
         }
-        result = subprocess.run(f"echo '{json.dumps(tree_json_cmd)}' | {binary_path}", shell=True, capture_output=True, text=True)
+        result = run_mcp_session(binary_path, tree_json_cmd)
         try:
             response = json.loads(result.stdout)
             if "result" not in response or "content" not in response["result"]:
@@ -194,7 +225,7 @@ def run_mcp_tests():
                 }
             }
         }
-        result = subprocess.run(f"echo '{json.dumps(tree_human_cmd)}' | {binary_path}", shell=True, capture_output=True, text=True)
+        result = run_mcp_session(binary_path, tree_human_cmd)
         try:
             response = json.loads(result.stdout)
             if "result" not in response or "content" not in response["result"]:
@@ -249,7 +280,7 @@ def run_mcp_tests():
                 }
             }
         }
-        result = subprocess.run(f"echo '{json.dumps(mcp_cmd1)}' | {binary_path}", shell=True, capture_output=True, text=True)
+        result = run_mcp_session(binary_path, mcp_cmd1)
         try:
             response = json.loads(result.stdout)
             if "result" not in response or "content" not in response["result"]:
@@ -280,7 +311,7 @@ def run_mcp_tests():
                 }
             }
         }
-        result = subprocess.run(f"echo '{json.dumps(mcp_cmd2)}' | {binary_path}", shell=True, capture_output=True, text=True)
+        result = run_mcp_session(binary_path, mcp_cmd2)
         try:
             response = json.loads(result.stdout)
             if "result" not in response or "content" not in response["result"]:
@@ -305,7 +336,7 @@ def run_mcp_tests():
             "id": 1,
             "method": "invalid/method"
         }
-        result = subprocess.run(f"echo '{json.dumps(error_cmd)}' | {binary_path}", shell=True, capture_output=True, text=True)
+        result = run_mcp_session(binary_path, error_cmd)
         try:
             response = json.loads(result.stdout)
             if "error" not in response or response["error"]["message"] != "Method not found":
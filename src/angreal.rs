@@ -1,7 +1,16 @@
+use crate::terminal::clean_output;
 use anyhow::{Context, Result};
-use std::process::Command;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::process;
+use tokio::sync::{mpsc, Mutex};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum AngrealError {
     NotInstalled,
     NotInProject,
@@ -35,7 +44,574 @@ pub async fn get_angreal_tree(format: &str) -> Result<String> {
         _ => unreachable!("Format already validated"),
     };
 
-    run_angreal_command("tree", &args).await
+    run_angreal_command("tree", &args, &RunOptions::default())
+        .await?
+        .into_result()
+}
+
+/// The commands of an angreal project, as reported by `angreal tree --json`.
+///
+/// Deserialization is lenient: unknown fields are ignored and missing or
+/// `null` fields fall back to their defaults, so newer angreal releases
+/// don't break parsing.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct CommandTree {
+    #[serde(default, deserialize_with = "nullable")]
+    pub commands: Vec<Command>,
+}
+
+impl CommandTree {
+    pub fn from_json(json: &str) -> Result<Self> {
+        serde_json::from_str(json).context("Failed to parse angreal command tree")
+    }
+
+    /// Finds the command invoked by a full argv such as
+    /// `["call-testing", "command-1", "--option"]`, returning it along with
+    /// the arguments that follow its path. The longest matching path wins.
+    pub fn resolve<'a>(&self, argv: &'a [String]) -> Option<(&Command, &'a [String])> {
+        self.commands
+            .iter()
+            .filter_map(|cmd| {
+                let path = cmd.path();
+                let words: Vec<&str> = path.split(' ').collect();
+                let matches = !path.is_empty()
+                    && argv.len() >= words.len()
+                    && words.iter().zip(argv).all(|(word, arg)| word == arg);
+                matches.then_some((cmd, words.len()))
+            })
+            .max_by_key(|(_, len)| *len)
+            .map(|(cmd, len)| (cmd, &argv[len..]))
+    }
+
+    /// Commands grouped by the path leading up to them, in the order the
+    /// groups first appear. Top-level commands are under the empty group.
+    pub fn groups(&self) -> Vec<(String, Vec<&Command>)> {
+        let mut groups: Vec<(String, Vec<&Command>)> = Vec::new();
+        for cmd in &self.commands {
+            let group = cmd.group_path();
+            match groups.iter_mut().find(|(name, _)| *name == group) {
+                Some((_, members)) => members.push(cmd),
+                None => groups.push((group, vec![cmd])),
+            }
+        }
+        groups
+    }
+}
+
+/// A single runnable angreal command.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct Command {
+    #[serde(default, deserialize_with = "nullable")]
+    pub name: String,
+    /// Full invocation path such as `call-testing command-1`; older angreal
+    /// releases only report `group` and `name`.
+    #[serde(default, deserialize_with = "nullable")]
+    pub path: String,
+    #[serde(default, deserialize_with = "nullable")]
+    pub group: String,
+    #[serde(default, deserialize_with = "nullable")]
+    pub description: String,
+    #[serde(default, deserialize_with = "nullable")]
+    pub arguments: Vec<Argument>,
+}
+
+impl Command {
+    /// The words to pass to angreal to reach this command, e.g.
+    /// `call-testing command-2`.
+    pub fn path(&self) -> String {
+        let path = normalize_path(&self.path);
+        if !path.is_empty() {
+            return path;
+        }
+        normalize_path(&format!("{} {}", self.group, self.name))
+    }
+
+    /// The path without the command's own name; empty for top-level
+    /// commands.
+    pub fn group_path(&self) -> String {
+        let path = self.path();
+        match path.rsplit_once(' ') {
+            Some((group, _)) => group.to_string(),
+            None => String::new(),
+        }
+    }
+
+    /// Usage line such as `call-testing command-3 [--verbose] <filename>`.
+    /// Optional arguments are wrapped in brackets.
+    pub fn signature(&self) -> String {
+        let mut signature = self.path();
+        for arg in &self.arguments {
+            signature.push(' ');
+            if arg.required {
+                signature.push_str(&arg.usage());
+            } else {
+                signature.push_str(&format!("[{}]", arg.usage()));
+            }
+        }
+        signature
+    }
+
+    /// Checks the arguments following the command's path against its
+    /// declared flags, options and positionals, the way angreal would parse
+    /// them. `--help` is always accepted.
+    pub fn check_args(&self, args: &[String]) -> std::result::Result<(), ArgumentErrors> {
+        let mut errors = ArgumentErrors::default();
+        let mut seen = vec![false; self.arguments.len()];
+        let mut positionals = Vec::new();
+
+        let mut iter = args.iter();
+        while let Some(arg) = iter.next() {
+            if arg == "--help" || arg == "-h" {
+                return Ok(());
+            }
+            if arg == "--" {
+                positionals.extend(iter.by_ref());
+                break;
+            }
+            if !arg.starts_with('-') || arg == "-" || is_number(arg) {
+                positionals.push(arg);
+                continue;
+            }
+
+            let (flag, inline_value) = match arg.split_once('=') {
+                Some((flag, value)) => (flag, Some(value)),
+                None => (arg.as_str(), None),
+            };
+            let declared = self.arguments.iter().position(|declared| {
+                declared.kind() != ArgumentKind::Positional && declared.cli_flag() == flag
+            });
+            let Some(index) = declared else {
+                errors.unknown.push(flag.to_string());
+                continue;
+            };
+            seen[index] = true;
+
+            match (self.arguments[index].kind(), inline_value) {
+                (ArgumentKind::Flag, Some(_)) => {
+                    errors
+                        .invalid
+                        .push(format!("{} does not take a value", flag));
+                }
+                (ArgumentKind::Flag, None) | (_, Some(_)) => {}
+                (_, None) => {
+                    if iter.next().is_none() {
+                        errors.invalid.push(format!("{} requires a value", flag));
+                    }
+                }
+            }
+        }
+
+        let mut supplied = positionals.into_iter();
+        for (index, declared) in self.arguments.iter().enumerate() {
+            if declared.kind() == ArgumentKind::Positional {
+                seen[index] = supplied.next().is_some();
+            }
+            if declared.required && !seen[index] {
+                errors.missing.push(declared.usage());
+            }
+        }
+        errors.unexpected.extend(supplied.cloned());
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+fn is_number(arg: &str) -> bool {
+    arg.parse::<f64>().is_ok()
+}
+
+/// Everything wrong with the arguments passed to a command.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ArgumentErrors {
+    /// Usage of each required argument that wasn't supplied.
+    pub missing: Vec<String>,
+    /// Flags the command doesn't declare.
+    pub unknown: Vec<String>,
+    /// Positional values beyond the ones the command declares.
+    pub unexpected: Vec<String>,
+    /// Other problems, such as an option without its value.
+    pub invalid: Vec<String>,
+}
+
+impl ArgumentErrors {
+    pub fn is_empty(&self) -> bool {
+        self.missing.is_empty()
+            && self.unknown.is_empty()
+            && self.unexpected.is_empty()
+            && self.invalid.is_empty()
+    }
+}
+
+impl std::fmt::Display for ArgumentErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut problems = Vec::new();
+        if !self.missing.is_empty() {
+            problems.push(format!(
+                "Missing required arguments: {}",
+                self.missing.join(", ")
+            ));
+        }
+        if !self.unknown.is_empty() {
+            problems.push(format!("Unknown arguments: {}", self.unknown.join(", ")));
+        }
+        if !self.unexpected.is_empty() {
+            problems.push(format!(
+                "Unexpected positional arguments: {}",
+                self.unexpected.join(", ")
+            ));
+        }
+        problems.extend(self.invalid.iter().cloned());
+        write!(f, "{}", problems.join("\n"))
+    }
+}
+
+impl std::error::Error for ArgumentErrors {}
+
+/// An argument accepted by a [`Command`].
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct Argument {
+    #[serde(default, deserialize_with = "nullable")]
+    pub name: String,
+    #[serde(default, deserialize_with = "nullable")]
+    pub flag: String,
+    #[serde(rename = "type", default, deserialize_with = "nullable")]
+    pub arg_type: ArgumentType,
+    #[serde(default, deserialize_with = "nullable")]
+    pub required: bool,
+    #[serde(default, deserialize_with = "nullable")]
+    pub help: String,
+}
+
+/// The `type` angreal reports for an argument.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ArgumentType {
+    Flag,
+    Parameter,
+    Positional,
+    /// Anything this version doesn't know about.
+    #[default]
+    #[serde(other)]
+    Unknown,
+}
+
+/// How an argument appears on the command line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArgumentKind {
+    /// `--flag`, present or absent.
+    Flag,
+    /// `--name <value>`.
+    Option,
+    /// A bare value, matched by position.
+    Positional,
+}
+
+impl Argument {
+    pub fn kind(&self) -> ArgumentKind {
+        match self.arg_type {
+            ArgumentType::Flag => ArgumentKind::Flag,
+            ArgumentType::Parameter => ArgumentKind::Option,
+            // Positionals declared with a long flag actually take `--flag <value>`
+            ArgumentType::Positional if self.flag.starts_with('-') => ArgumentKind::Option,
+            ArgumentType::Positional => ArgumentKind::Positional,
+            ArgumentType::Unknown if self.flag.is_empty() => ArgumentKind::Positional,
+            ArgumentType::Unknown => ArgumentKind::Option,
+        }
+    }
+
+    /// The flag that introduces this argument, falling back to `--<name>`.
+    pub fn cli_flag(&self) -> String {
+        if self.flag.is_empty() {
+            format!("--{}", self.name)
+        } else {
+            self.flag.clone()
+        }
+    }
+
+    /// How the argument is written in a usage line: `--flag`,
+    /// `--name <value>` or `<name>`.
+    pub fn usage(&self) -> String {
+        match self.kind() {
+            ArgumentKind::Flag => self.cli_flag(),
+            ArgumentKind::Option => format!("{} <value>", self.cli_flag()),
+            ArgumentKind::Positional => format!("<{}>", self.name),
+        }
+    }
+}
+
+fn normalize_path(path: &str) -> String {
+    let path = path.split_whitespace().collect::<Vec<_>>().join(" ");
+    match path.strip_prefix("angreal ") {
+        Some(rest) => rest.to_string(),
+        None if path == "angreal" => String::new(),
+        None => path,
+    }
+}
+
+/// Treats an explicit `null` like a missing field.
+fn nullable<'de, D, T>(deserializer: D) -> std::result::Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Default + Deserialize<'de>,
+{
+    Ok(Option::<T>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+/// Keeps the output of `angreal tree --json` per project root so it isn't
+/// recomputed, at the cost of a Python start, on every request. An entry is
+/// reloaded once any `.py` file under `.angreal/` or an `angreal.toml` is
+/// added, removed or modified.
+#[derive(Debug, Default)]
+pub struct TreeCache {
+    entries: Mutex<HashMap<PathBuf, CacheEntry>>,
+    version: Mutex<Option<String>>,
+    /// The angreal executable to run instead of the one on `PATH`.
+    program: Option<PathBuf>,
+    /// Directory [`load`](Self::load) serves the project of, instead of
+    /// the current one.
+    dir: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    fingerprint: Fingerprint,
+    /// Failures caused by the project itself are kept too, so a broken task
+    /// file doesn't cost an `angreal tree` on every request.
+    result: std::result::Result<TreeSnapshot, AngrealError>,
+}
+
+/// A command tree as handed out by the [`TreeCache`].
+#[derive(Debug, Clone)]
+pub struct TreeSnapshot {
+    /// Directory containing the project's `.angreal/`.
+    pub root: PathBuf,
+    pub tree: Arc<CommandTree>,
+    /// The raw `angreal tree --json` output the tree was parsed from.
+    pub json: Arc<str>,
+    pub loaded_at: SystemTime,
+    /// Whether this came out of the cache rather than a fresh `angreal tree`.
+    pub from_cache: bool,
+}
+
+impl TreeSnapshot {
+    /// One-line description of where the tree came from and how old it is.
+    pub fn freshness(&self) -> String {
+        let age = self.loaded_at.elapsed().unwrap_or_default().as_secs();
+        if self.from_cache {
+            format!(
+                "Served from cache: loaded {}s ago, no changes to .angreal/ since",
+                age
+            )
+        } else {
+            "Freshly loaded from `angreal tree --json`".to_string()
+        }
+    }
+}
+
+impl TreeCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Runs `program` instead of the `angreal` on `PATH`.
+    pub fn with_program(mut self, program: impl Into<PathBuf>) -> Self {
+        self.program = Some(program.into());
+        self
+    }
+
+    /// Serves the project containing `dir` from [`load`](Self::load)
+    /// instead of the one containing the current directory.
+    pub fn in_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.dir = Some(dir.into());
+        self
+    }
+
+    /// Returns the command tree for the project containing the current
+    /// directory, running `angreal tree --json` only if the project's task
+    /// files changed since it was last loaded.
+    pub async fn load(&self) -> Result<TreeSnapshot> {
+        self.load_in(&self.dir()?).await
+    }
+
+    /// The directory [`load`](Self::load) serves the project of.
+    pub fn dir(&self) -> Result<PathBuf> {
+        match &self.dir {
+            Some(dir) => Ok(dir.clone()),
+            None => std::env::current_dir().context("Failed to read current directory"),
+        }
+    }
+
+    /// Root of the project [`load`](Self::load) serves, if there is one.
+    pub fn project_root(&self) -> Option<PathBuf> {
+        find_project_root(&self.dir().ok()?)
+    }
+
+    /// Like [`load`](Self::load), for the project containing `dir`.
+    pub async fn load_in(&self, dir: &Path) -> Result<TreeSnapshot> {
+        let Some(root) = find_project_root(dir) else {
+            // Nothing to key or watch; let angreal report the problem
+            return self.fetch_tree(dir.to_path_buf()).await;
+        };
+
+        // Held across the load so concurrent requests share one `angreal tree`
+        let mut entries = self.entries.lock().await;
+        let fingerprint = Fingerprint::of(&root);
+        if let Some(entry) = entries.get(&root) {
+            if entry.fingerprint == fingerprint {
+                return match &entry.result {
+                    Ok(snapshot) => Ok(TreeSnapshot {
+                        from_cache: true,
+                        ..snapshot.clone()
+                    }),
+                    Err(e) => Err(e.clone().into()),
+                };
+            }
+        }
+
+        let loaded = self.fetch_tree(root.clone()).await;
+        let result = match &loaded {
+            Ok(snapshot) => Ok(snapshot.clone()),
+            Err(e) => match e.downcast_ref::<AngrealError>() {
+                Some(e @ (AngrealError::NotInProject | AngrealError::ExecutionFailed(_))) => {
+                    Err(e.clone())
+                }
+                // Angreal missing or unparsable output: try again next time
+                _ => return loaded,
+            },
+        };
+        entries.insert(
+            root,
+            CacheEntry {
+                fingerprint,
+                result,
+            },
+        );
+        loaded
+    }
+
+    /// Output of `angreal --version`, or `None` if angreal can't be run.
+    /// Only successful lookups are remembered.
+    pub async fn version(&self) -> Option<String> {
+        let mut version = self.version.lock().await;
+        if version.is_none() {
+            let output = process::Command::new(self.program())
+                .arg("--version")
+                .output()
+                .await
+                .ok()
+                .filter(|output| output.status.success())?;
+            *version = Some(String::from_utf8_lossy(&output.stdout).trim().to_string());
+        }
+        version.clone()
+    }
+
+    fn program(&self) -> &Path {
+        self.program.as_deref().unwrap_or("angreal".as_ref())
+    }
+
+    /// Runs `angreal tree --json` in `root`.
+    async fn fetch_tree(&self, root: PathBuf) -> Result<TreeSnapshot> {
+        let options = RunOptions {
+            program: self.program.clone(),
+            cwd: Some(root.clone()),
+            ..RunOptions::default()
+        };
+        let json = run_angreal_command("tree", &["--json".to_string()], &options)
+            .await?
+            .into_result()?;
+        Ok(TreeSnapshot {
+            root,
+            tree: Arc::new(CommandTree::from_json(&json)?),
+            json: json.into(),
+            loaded_at: SystemTime::now(),
+            from_cache: false,
+        })
+    }
+}
+
+/// The nearest directory at or above `start` that contains `.angreal/`.
+pub fn find_project_root(start: &Path) -> Option<PathBuf> {
+    start
+        .ancestors()
+        .find(|dir| dir.join(".angreal").is_dir())
+        .map(Path::to_path_buf)
+}
+
+/// Resolves `requested` (relative to `base`) to a directory inside the
+/// angreal project containing `base`. Symlinks and `..` are resolved first,
+/// so neither can be used to step outside the project.
+pub fn resolve_working_dir(base: &Path, requested: &Path) -> std::result::Result<PathBuf, String> {
+    let root = find_project_root(base)
+        .ok_or_else(|| "cwd can only be used inside an angreal project".to_string())?;
+    let root = root
+        .canonicalize()
+        .map_err(|e| format!("Cannot resolve project root {}: {}", root.display(), e))?;
+    let dir = base
+        .join(requested)
+        .canonicalize()
+        .map_err(|e| format!("Cannot use cwd '{}': {}", requested.display(), e))?;
+
+    if !dir.is_dir() {
+        return Err(format!("cwd '{}' is not a directory", requested.display()));
+    }
+    if !dir.starts_with(&root) {
+        return Err(format!(
+            "cwd '{}' is outside the project root {}",
+            requested.display(),
+            root.display()
+        ));
+    }
+    Ok(dir)
+}
+
+/// Path, modification time and size of every file that can change a
+/// project's command tree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Fingerprint(Vec<(PathBuf, Option<SystemTime>, u64)>);
+
+impl Fingerprint {
+    fn of(root: &Path) -> Self {
+        let mut files = Vec::new();
+        collect_task_files(&root.join(".angreal"), &mut files);
+        let toml = root.join("angreal.toml");
+        if toml.is_file() {
+            files.push(toml);
+        }
+
+        let mut entries: Vec<_> = files
+            .into_iter()
+            .map(|path| {
+                let metadata = std::fs::metadata(&path).ok();
+                let modified = metadata.as_ref().and_then(|m| m.modified().ok());
+                let len = metadata.map_or(0, |m| m.len());
+                (path, modified, len)
+            })
+            .collect();
+        entries.sort();
+        Self(entries)
+    }
+}
+
+fn collect_task_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            if entry.file_name() != "__pycache__" {
+                collect_task_files(&path, files);
+            }
+        } else if path.extension().is_some_and(|ext| ext == "py")
+            || entry.file_name() == "angreal.toml"
+        {
+            files.push(path);
+        }
+    }
 }
 
 fn validate_format(format: &str) -> Result<()> {
@@ -45,25 +621,17 @@ fn validate_format(format: &str) -> Result<()> {
     }
 }
 
-pub async fn check_angreal_available() -> Result<bool> {
-    match Command::new("angreal").arg("--version").output() {
-        Ok(output) => Ok(output.status.success()),
-        Err(_) => Ok(false),
-    }
-}
-
-pub async fn check_angreal_project_status() -> Result<String> {
+pub async fn check_angreal_project_status(cache: &TreeCache) -> Result<String> {
     let mut status_parts = Vec::new();
     let mut command_tree = None;
 
     // Check if angreal is installed
-    let angreal_available = match Command::new("angreal").arg("--version").output() {
-        Ok(output) if output.status.success() => {
-            let version = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    let angreal_available = match cache.version().await {
+        Some(version) => {
             status_parts.push(format!(" Angreal is installed: {}", version));
             true
         }
-        _ => {
+        None => {
             status_parts.push("  Angreal is not installed or not available in PATH".to_string());
             status_parts.push("  Install angreal first: pip install angreal".to_string());
             false
@@ -81,38 +649,34 @@ pub async fn check_angreal_project_status() -> Result<String> {
 
     // If both are available, check project initialization status and get command tree
     if angreal_available && angreal_folder_exists {
-        match Command::new("angreal").arg("tree").arg("--json").output() {
-            Ok(output) if output.status.success() => {
-                let tree_output = String::from_utf8_lossy(&output.stdout);
-                if tree_output.trim().is_empty() || tree_output.contains("No commands") {
-                    status_parts.push(
-                        "  Project appears to be initialized but has no commands defined"
-                            .to_string(),
-                    );
-                    status_parts
-                        .push("  You may need to add tasks in the .angreal/ directory".to_string());
-                } else {
-                    status_parts.push(
-                        "✓ Project is properly initialized with available commands".to_string(),
-                    );
-                    command_tree = Some(tree_output.to_string());
-                }
+        match cache.load().await {
+            Ok(snapshot) if snapshot.tree.commands.is_empty() => {
+                status_parts.push(
+                    "  Project appears to be initialized but has no commands defined".to_string(),
+                );
+                status_parts
+                    .push("  You may need to add tasks in the .angreal/ directory".to_string());
             }
-            Ok(output) => {
-                let stderr = String::from_utf8_lossy(&output.stderr);
-                if stderr.contains("No angreal.toml") || stderr.contains("not an angreal project") {
+            Ok(snapshot) => {
+                status_parts
+                    .push("✓ Project is properly initialized with available commands".to_string());
+                command_tree = Some(snapshot.json.to_string());
+            }
+            Err(e) => match e.downcast_ref::<AngrealError>() {
+                Some(AngrealError::NotInProject) => {
                     status_parts.push(
                         "✗ Project folder exists but may not be properly initialized".to_string(),
                     );
                     status_parts
                         .push("  Try running 'angreal init' to initialize the project".to_string());
-                } else {
+                }
+                Some(AngrealError::ExecutionFailed(stderr)) => {
                     status_parts.push(format!("⚠ Angreal tree command failed: {}", stderr.trim()));
                 }
-            }
-            Err(e) => {
-                status_parts.push(format!("⚠ Could not check project status: {}", e));
-            }
+                _ => {
+                    status_parts.push(format!("⚠ Could not check project status: {}", e));
+                }
+            },
         }
     } else if !angreal_available && angreal_folder_exists {
         status_parts.push("  Install angreal to work with this project".to_string());
@@ -137,55 +701,379 @@ pub async fn check_angreal_project_status() -> Result<String> {
     Ok(result)
 }
 
-pub async fn run_angreal_command(command: &str, args: &[String]) -> Result<String> {
+/// Options controlling how a single angreal invocation is executed.
+#[derive(Debug, Clone, Default)]
+pub struct RunOptions {
+    /// The angreal executable to run instead of the one on `PATH`.
+    pub program: Option<PathBuf>,
+    /// Kill the task, and every process in its process group, once it has
+    /// run for this long.
+    pub timeout: Option<Duration>,
+    /// Receives each line of output as soon as the task prints it.
+    pub output_lines: Option<mpsc::UnboundedSender<OutputLine>>,
+    /// Only forward output to `output_lines` instead of also collecting it
+    /// in the returned [`CommandOutput`], for tasks whose output would
+    /// otherwise grow without bound.
+    pub stream_only: bool,
+    /// Receives the task's process id, which is also its process group id,
+    /// once it has been spawned.
+    pub pid: Option<mpsc::UnboundedSender<u32>>,
+    /// Ask the task for plain output by setting `NO_COLOR=1` and
+    /// `TERM=dumb`. Escape sequences are stripped from the output either
+    /// way; this just saves tools from drawing colours and progress bars.
+    pub plain_output: bool,
+    /// Attach the task to a pseudo-terminal instead of pipes, for tasks
+    /// that need a TTY. Stdout and stderr then arrive as one stream, which
+    /// is reported as stdout.
+    pub pty: bool,
+    /// Extra environment variables, applied on top of the server's own
+    /// environment (and of `plain_output`).
+    pub env: Vec<(String, String)>,
+    /// Directory to run the task in instead of the server's.
+    pub cwd: Option<PathBuf>,
+    /// Text written to the task's input, which is then closed. Without it
+    /// the input is closed from the start, so prompts fail instead of
+    /// waiting forever.
+    pub stdin: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+/// A single line of task output, without its line terminator.
+#[derive(Debug, Clone, Serialize)]
+pub struct OutputLine {
+    pub stream: OutputStream,
+    pub text: String,
+}
+
+/// Everything captured from an angreal invocation.
+#[derive(Debug)]
+pub struct CommandOutput {
+    /// The full command line that was run, starting with `angreal`.
+    pub argv: Vec<String>,
+    pub stdout: String,
+    pub stderr: String,
+    /// Exit status of the angreal process; `None` if it was killed on timeout.
+    pub status: Option<ExitStatus>,
+    /// Whether the task hit its time limit. Output is partial in that case.
+    pub timed_out: bool,
+    /// Wall-clock time from spawning the task until it exited or was killed.
+    pub duration: Duration,
+}
+
+impl CommandOutput {
+    /// The process's exit code; `None` if it was killed by a signal or on
+    /// timeout.
+    pub fn exit_code(&self) -> Option<i32> {
+        self.status.and_then(|status| status.code())
+    }
+
+    /// The signal that ended the process, if any.
+    #[cfg(unix)]
+    pub fn signal(&self) -> Option<i32> {
+        use std::os::unix::process::ExitStatusExt;
+        self.status.and_then(|status| status.signal())
+    }
+
+    #[cfg(not(unix))]
+    pub fn signal(&self) -> Option<i32> {
+        None
+    }
+
+    /// Whether a failed task seems to have wanted input it didn't get, such
+    /// as a prompt that found its input closed.
+    pub fn ran_out_of_input(&self) -> bool {
+        let success = self.status.is_some_and(|status| status.success());
+        !success
+            && [&self.stdout, &self.stderr].iter().any(|text| {
+                text.contains("EOFError") || text.lines().any(|line| line.trim() == "Aborted!")
+            })
+    }
+
+    /// Converts the output into the text shown to the client, mapping
+    /// non-zero exits onto the matching `AngrealError`.
+    pub fn into_result(self) -> Result<String> {
+        let success = self.status.is_some_and(|status| status.success());
+        let (stdout, stderr) = (self.stdout, self.stderr);
+
+        if success {
+            // Combine stdout and stderr for complete output
+            if stderr.trim().is_empty() {
+                Ok(stdout)
+            } else {
+                Ok(format!("{}\n\nStderr:\n{}", stdout, stderr))
+            }
+        } else {
+            // Check for common error patterns
+            if stderr.contains("command not found")
+                || self.status.and_then(|status| status.code()) == Some(127)
+            {
+                return Err(AngrealError::NotInstalled.into());
+            }
+
+            if stderr.contains("No angreal.toml") || stderr.contains("not an angreal project") {
+                return Err(AngrealError::NotInProject.into());
+            }
+
+            // Provide helpful error with both stdout and stderr
+            let error_output = if stdout.trim().is_empty() {
+                stderr
+            } else if stderr.trim().is_empty() {
+                stdout
+            } else {
+                format!("Output:\n{}\n\nError:\n{}", stdout, stderr)
+            };
+
+            Err(AngrealError::ExecutionFailed(error_output).into())
+        }
+    }
+}
+
+pub async fn run_angreal_command(
+    command: &str,
+    args: &[String],
+    options: &RunOptions,
+) -> Result<CommandOutput> {
     // Enhanced validation: allow more complex command structures
     validate_angreal_command(command)?;
 
     // Parse command to handle potential subcommands
     let all_args = parse_command_and_args(command, args)?;
 
-    // Prepare to execute angreal command
+    let mut cmd = process::Command::new(options.program.as_deref().unwrap_or("angreal".as_ref()));
+    cmd.args(&all_args).kill_on_drop(true);
+    if options.plain_output {
+        cmd.env("NO_COLOR", "1").env("TERM", "dumb");
+    }
+    cmd.envs(options.env.iter().map(|(name, value)| (name, value)));
+    if let Some(cwd) = &options.cwd {
+        cmd.current_dir(cwd);
+    }
 
-    let output = Command::new("angreal")
-        .args(&all_args)
-        .output()
-        .context("Failed to execute angreal command")?;
+    #[cfg(unix)]
+    let terminal = if options.pty {
+        Some(crate::pty::attach(&mut cmd).context("Failed to open a pseudo-terminal")?)
+    } else {
+        // Run the task in its own process group so a timeout can take down
+        // everything it spawned, not just the angreal process itself.
+        cmd.process_group(0);
+        None
+    };
+    #[cfg(not(unix))]
+    let terminal: Option<tokio::fs::File> = if options.pty {
+        anyhow::bail!("Pseudo-terminals are only supported on Unix");
+    } else {
+        None
+    };
 
-    // Handle both success and failure cases
-    if output.status.success() {
-        let stdout = String::from_utf8_lossy(&output.stdout);
-        let stderr = String::from_utf8_lossy(&output.stderr);
+    if terminal.is_none() {
+        let stdin = match options.stdin {
+            Some(_) => Stdio::piped(),
+            None => Stdio::null(),
+        };
+        cmd.stdin(stdin)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+    }
 
-        // Combine stdout and stderr for complete output
-        if stderr.trim().is_empty() {
-            Ok(stdout.to_string())
-        } else {
-            Ok(format!("{}\n\nStderr:\n{}", stdout, stderr))
+    let started = Instant::now();
+    let mut child = match cmd.spawn() {
+        Ok(child) => child,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(AngrealError::NotInstalled.into());
         }
-    } else {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        let stdout = String::from_utf8_lossy(&output.stdout);
+        Err(e) => return Err(e).context("Failed to execute angreal command"),
+    };
+    // Closes our copies of the terminal's slave side, so reading the master
+    // ends once the task and everything it started have exited
+    drop(cmd);
+    let mut guard = ProcessGroupGuard::new(child.id());
+    if let (Some(pid_tx), Some(pid)) = (&options.pid, child.id()) {
+        let _ = pid_tx.send(pid);
+    }
 
-        // Check for common error patterns
-        if stderr.contains("command not found") || output.status.code() == Some(127) {
-            return Err(AngrealError::NotInstalled.into());
+    let mut input = options.stdin.clone().unwrap_or_default().into_bytes();
+    let (mut stdout, input_pipe): (
+        Box<dyn AsyncRead + Unpin + Send>,
+        Option<Box<dyn AsyncWrite + Unpin + Send>>,
+    ) = match terminal {
+        Some(master) => {
+            // A terminal can't be closed for input, so end it the way a
+            // user would, with an end-of-file character
+            #[cfg(unix)]
+            crate::pty::terminate_input(&mut input);
+            let writer = master
+                .try_clone()
+                .await
+                .context("Failed to open the pseudo-terminal for input")?;
+            (Box::new(master), Some(Box::new(writer)))
         }
+        None => (
+            Box::new(child.stdout.take().context("Failed to capture stdout")?),
+            child
+                .stdin
+                .take()
+                .map(|stdin| Box::new(stdin) as Box<dyn AsyncWrite + Unpin + Send>),
+        ),
+    };
+    let mut stderr = child.stderr.take();
+    let mut stdout_buf = Vec::new();
+    let mut stderr_buf = Vec::new();
 
-        if stderr.contains("No angreal.toml") || stderr.contains("not an angreal project") {
-            return Err(AngrealError::NotInProject.into());
+    let lines = options.output_lines.as_ref();
+    let keep = !options.stream_only;
+    let run = async {
+        let (status, stdout_read, stderr_read, ()) = tokio::join!(
+            child.wait(),
+            async {
+                match capture(
+                    &mut stdout,
+                    OutputStream::Stdout,
+                    &mut stdout_buf,
+                    lines,
+                    keep,
+                )
+                .await
+                {
+                    #[cfg(unix)]
+                    Err(e) if options.pty && crate::pty::is_hangup(&e) => Ok(()),
+                    other => other,
+                }
+            },
+            async {
+                match &mut stderr {
+                    Some(stderr) => {
+                        capture(stderr, OutputStream::Stderr, &mut stderr_buf, lines, keep).await
+                    }
+                    None => Ok(()),
+                }
+            },
+            async {
+                if let Some(mut pipe) = input_pipe {
+                    // Tasks are free to exit without reading all of it
+                    let _ = async {
+                        pipe.write_all(&input).await?;
+                        pipe.flush().await
+                    }
+                    .await;
+                }
+            },
+        );
+        stdout_read?;
+        stderr_read?;
+        status
+    };
+
+    let status = match options.timeout {
+        Some(limit) => tokio::time::timeout(limit, run).await.ok(),
+        None => Some(run.await),
+    };
+
+    let (status, timed_out) = match status {
+        Some(status) => {
+            guard.disarm();
+            (
+                Some(status.context("Failed to wait for angreal command")?),
+                false,
+            )
+        }
+        None => {
+            guard.kill();
+            // Reap the killed process; its exit status carries no information.
+            let _ = child.wait().await;
+            (None, true)
         }
+    };
 
-        // Provide helpful error with both stdout and stderr
-        let error_output = if stdout.trim().is_empty() {
-            stderr.to_string()
-        } else {
-            format!("Output:\n{}\n\nError:\n{}", stdout, stderr)
-        };
+    Ok(CommandOutput {
+        argv: std::iter::once("angreal".to_string())
+            .chain(all_args)
+            .collect(),
+        stdout: clean_output(&String::from_utf8_lossy(&stdout_buf)),
+        stderr: clean_output(&String::from_utf8_lossy(&stderr_buf)),
+        status,
+        timed_out,
+        duration: started.elapsed(),
+    })
+}
 
-        Err(AngrealError::ExecutionFailed(error_output).into())
+/// Reads `reader` to EOF into `buf`, forwarding each completed line to
+/// `lines` as it arrives, cleaned of escape sequences. Whatever was read
+/// stays in `buf` if the future is dropped part way through; with `keep`
+/// unset lines are only forwarded.
+async fn capture<R: AsyncRead + Unpin>(
+    reader: R,
+    stream: OutputStream,
+    buf: &mut Vec<u8>,
+    lines: Option<&mpsc::UnboundedSender<OutputLine>>,
+    keep: bool,
+) -> std::io::Result<()> {
+    let mut reader = BufReader::new(reader);
+    loop {
+        let start = buf.len();
+        if reader.read_until(b'\n', buf).await? == 0 {
+            return Ok(());
+        }
+        if let Some(lines) = lines {
+            let text = String::from_utf8_lossy(&buf[start..]);
+            let _ = lines.send(OutputLine {
+                stream,
+                text: clean_output(text.trim_end_matches(['\n', '\r'])),
+            });
+        }
+        if !keep {
+            buf.truncate(start);
+        }
     }
 }
 
+/// Kills a child's process group when dropped unless disarmed, so abandoned
+/// tasks never leave grandchildren (cargo, pytest, ...) running.
+struct ProcessGroupGuard {
+    pid: Option<u32>,
+}
+
+impl ProcessGroupGuard {
+    fn new(pid: Option<u32>) -> Self {
+        Self { pid }
+    }
+
+    fn disarm(&mut self) {
+        self.pid = None;
+    }
+
+    fn kill(&mut self) {
+        if let Some(pid) = self.pid.take() {
+            kill_process_group(pid);
+        }
+    }
+}
+
+impl Drop for ProcessGroupGuard {
+    fn drop(&mut self) {
+        self.kill();
+    }
+}
+
+#[cfg(unix)]
+pub(crate) fn kill_process_group(pid: u32) {
+    // SAFETY: killpg has no memory-safety preconditions; failures (e.g. the
+    // group already exited) are deliberately ignored.
+    unsafe {
+        libc::killpg(pid as libc::pid_t, libc::SIGKILL);
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn kill_process_group(_pid: u32) {
+    // No process groups here; `kill_on_drop` takes care of the direct child.
+}
+
 fn validate_angreal_command(command: &str) -> Result<()> {
     // Allow more flexible command structures including subcommands
     let parts: Vec<&str> = command.split_whitespace().collect();
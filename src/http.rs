@@ -0,0 +1,595 @@
+//! Streamable HTTP transport, so several clients can share one server
+//! process. Each client gets its own MCP session; the tree cache,
+//! background jobs and stored runs are shared between them.
+
+use crate::mcp::{
+    invalid_request, is_client_response, parse_error, parse_request, JsonRpcMessage,
+    JsonRpcRequest, McpServer, SUPPORTED_PROTOCOL_VERSIONS, TOOL_WATCH_INTERVAL,
+};
+use crate::transport;
+use bytes::Bytes;
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::{Frame, Incoming};
+use hyper::header::{self, HeaderValue};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// Path of the MCP endpoint.
+pub const ENDPOINT: &str = "/mcp";
+
+const SESSION_HEADER: &str = "mcp-session-id";
+const PROTOCOL_VERSION_HEADER: &str = "mcp-protocol-version";
+
+/// Largest request body accepted.
+const MAX_BODY_BYTES: usize = 4 * 1024 * 1024;
+
+/// How long a session may go without requests before it's closed, unless
+/// it still has requests running or an event stream open.
+pub const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+type Body = BoxBody<Bytes, Infallible>;
+
+pub struct HttpServer {
+    /// Template for new sessions; it never handles requests itself.
+    base: McpServer,
+    sessions: Mutex<HashMap<String, Arc<Session>>>,
+    idle_timeout: Duration,
+}
+
+struct Session {
+    server: Arc<McpServer>,
+    routes: Arc<Routes>,
+    /// The channel the server sends its notifications on. Responses that
+    /// end a progress stream go through it too, behind the progress.
+    outgoing: mpsc::UnboundedSender<JsonRpcMessage>,
+    /// The tool watcher and the task routing the session's notifications.
+    tasks: Vec<JoinHandle<()>>,
+    /// When the client last sent the session a message.
+    last_active: Mutex<Instant>,
+}
+
+/// Where a session's server-initiated messages go.
+#[derive(Default)]
+struct Routes {
+    /// Response streams of POSTed requests, keyed by the JSON of the
+    /// progress token the request carried.
+    progress: Mutex<HashMap<String, RequestStream>>,
+    /// The stream opened with GET, for messages not tied to a request.
+    events: Mutex<Option<mpsc::UnboundedSender<JsonRpcMessage>>>,
+}
+
+/// The response stream of a POSTed request that asked for progress.
+struct RequestStream {
+    /// JSON of the request's id, to find the stream its response ends.
+    id: String,
+    messages: mpsc::UnboundedSender<JsonRpcMessage>,
+}
+
+impl Routes {
+    fn route(&self, message: JsonRpcMessage) {
+        match &message {
+            // Progress belongs on the stream of the request it reports on,
+            // if it has one; batched requests and those answered with plain
+            // JSON report on the event stream
+            JsonRpcMessage::Notification(notification) => {
+                if let Some(token) = notification
+                    .params
+                    .as_ref()
+                    .and_then(|params| params.get("progressToken"))
+                {
+                    if let Some(stream) = self.progress.lock().unwrap().get(&token.to_string()) {
+                        let _ = stream.messages.send(message);
+                        return;
+                    }
+                }
+            }
+            // The response comes after all of the request's progress, so
+            // it closes the stream
+            JsonRpcMessage::Response(response) => {
+                let id = serde_json::to_string(&response.id).unwrap_or_default();
+                let mut progress = self.progress.lock().unwrap();
+                let token = progress
+                    .iter()
+                    .find(|(_, stream)| stream.id == id)
+                    .map(|(token, _)| token.clone());
+                if let Some(stream) = token.and_then(|token| progress.remove(&token)) {
+                    let _ = stream.messages.send(message);
+                }
+                return;
+            }
+            JsonRpcMessage::Batch(_) => {}
+        }
+        // Anything else waits for nobody: without a GET stream it's dropped
+        if let Some(events) = self.events.lock().unwrap().as_ref() {
+            let _ = events.send(message);
+        }
+    }
+}
+
+impl Session {
+    fn start(base: &McpServer) -> Arc<Self> {
+        let (outgoing, mut rx) = mpsc::unbounded_channel();
+        let server = Arc::new(base.session().with_notifier(outgoing.clone()));
+        let routes = Arc::new(Routes::default());
+
+        let router = {
+            let routes = Arc::clone(&routes);
+            tokio::spawn(async move {
+                while let Some(message) = rx.recv().await {
+                    routes.route(message);
+                }
+            })
+        };
+        let watcher = server.watch_tools(TOOL_WATCH_INTERVAL);
+
+        Arc::new(Self {
+            server,
+            routes,
+            outgoing,
+            tasks: vec![router, watcher],
+            last_active: Mutex::new(Instant::now()),
+        })
+    }
+
+    /// Whether the client seems to have gone away: nothing has been sent
+    /// for `timeout`, nothing is running and no event stream is open.
+    fn is_idle(&self, timeout: Duration) -> bool {
+        let has_events = self
+            .routes
+            .events
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|events| !events.is_closed());
+        self.last_active.lock().unwrap().elapsed() >= timeout
+            && !self.server.has_requests_in_flight()
+            && !has_events
+    }
+
+    /// Ends the session, cancelling whatever it still has running and
+    /// closing its event stream.
+    fn close(&self) {
+        self.server.begin_shutdown();
+        self.server.cancel_all();
+        for task in &self.tasks {
+            task.abort();
+        }
+        self.routes.events.lock().unwrap().take();
+    }
+}
+
+impl HttpServer {
+    pub fn new(base: McpServer) -> Self {
+        Self {
+            base,
+            sessions: Mutex::new(HashMap::new()),
+            idle_timeout: SESSION_IDLE_TIMEOUT,
+        }
+    }
+
+    /// Closes sessions after `timeout` without activity instead of
+    /// [`SESSION_IDLE_TIMEOUT`].
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Serves HTTP on `listener` until `shutdown` resolves, then closes every
+    /// session and kills the background jobs.
+    pub async fn serve(
+        self: Arc<Self>,
+        listener: TcpListener,
+        shutdown: impl Future<Output = ()>,
+    ) -> std::io::Result<()> {
+        // Clients that vanish without a DELETE would otherwise keep their
+        // sessions forever
+        let idle_check = {
+            let http = Arc::clone(&self);
+            tokio::spawn(async move {
+                let period = (http.idle_timeout / 2).max(Duration::from_millis(1));
+                let mut ticks = tokio::time::interval(period);
+                loop {
+                    ticks.tick().await;
+                    http.close_idle_sessions();
+                }
+            })
+        };
+
+        let connections = transport::accept(
+            || async { listener.accept().await.map(|(stream, _)| stream) },
+            |stream, stop| {
+                let http = Arc::clone(&self);
+                async move {
+                    let service = service_fn(move |request| {
+                        let http = Arc::clone(&http);
+                        async move { Ok::<_, Infallible>(http.handle(request).await) }
+                    });
+                    let connection =
+                        http1::Builder::new().serve_connection(TokioIo::new(stream), service);
+                    tokio::pin!(connection);
+                    tokio::select! {
+                        _ = connection.as_mut() => return,
+                        () = stop.requested() => connection.as_mut().graceful_shutdown(),
+                    }
+                    let _ = connection.await;
+                }
+            },
+            shutdown,
+        )
+        .await;
+
+        // Closing the sessions ends their event streams, which lets the
+        // connections carrying them finish
+        idle_check.abort();
+        for (_, session) in self.sessions.lock().unwrap().drain() {
+            session.close();
+        }
+        connections.close().await;
+        self.base.kill_jobs();
+        Ok(())
+    }
+
+    async fn handle(&self, request: Request<Incoming>) -> Response<Body> {
+        if request.uri().path() != ENDPOINT {
+            return status(StatusCode::NOT_FOUND, "Not found");
+        }
+        // Refuse pages in a browser that try to reach the server through
+        // DNS rebinding
+        if let Some(origin) = request.headers().get(header::ORIGIN) {
+            if !origin.to_str().is_ok_and(is_local_origin) {
+                return status(StatusCode::FORBIDDEN, "Origin not allowed");
+            }
+        }
+        if let Some(version) = request.headers().get(PROTOCOL_VERSION_HEADER) {
+            if !version
+                .to_str()
+                .is_ok_and(|version| SUPPORTED_PROTOCOL_VERSIONS.contains(&version))
+            {
+                return status(StatusCode::BAD_REQUEST, "Unsupported MCP-Protocol-Version");
+            }
+        }
+
+        match *request.method() {
+            Method::POST => self.post(request).await,
+            Method::GET => self.get(&request),
+            Method::DELETE => self.delete(&request),
+            _ => {
+                let mut response = status(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed");
+                response
+                    .headers_mut()
+                    .insert(header::ALLOW, HeaderValue::from_static("GET, POST, DELETE"));
+                response
+            }
+        }
+    }
+
+    /// Handles a message from the client. Requests are answered with a
+    /// single JSON response, or with an event stream carrying the request's
+    /// progress notifications followed by the response if the client
+    /// accepts one.
+    async fn post(&self, request: Request<Incoming>) -> Response<Body> {
+        let wants_stream = accepts(&request, "text/event-stream");
+        let session = session_id(&request);
+
+        let body = match Limited::new(request.into_body(), MAX_BODY_BYTES)
+            .collect()
+            .await
+        {
+            Ok(body) => body.to_bytes(),
+            Err(_) => return status(StatusCode::PAYLOAD_TOO_LARGE, "Request body too large"),
+        };
+        let message: Value = match serde_json::from_slice(&body) {
+            Ok(message) => message,
+            Err(_) => return json(StatusCode::BAD_REQUEST, &parse_error().into()),
+        };
+        // Responses to server requests; the server never sends any
+        if is_client_response(&message) {
+            return status(StatusCode::ACCEPTED, "");
+        }
+        if let Value::Array(batch) = message {
+            return self.batch(session.as_deref(), batch).await;
+        }
+        let message = match parse_request(message) {
+            Ok(message) => message,
+            Err(rejection) => return json(StatusCode::BAD_REQUEST, &rejection.response().into()),
+        };
+
+        if message.method == "initialize" {
+            return self.initialize(message).await;
+        }
+        let session = match self.find_session(session.as_deref()) {
+            Ok(session) => session,
+            Err((code, message)) => return status(code, message),
+        };
+
+        if message.is_notification() {
+            session.server.handle_notification(message);
+            return status(StatusCode::ACCEPTED, "");
+        }
+
+        let progress_token = message
+            .params
+            .as_ref()
+            .and_then(|params| params.get("_meta"))
+            .and_then(|meta| meta.get("progressToken"))
+            .filter(|_| wants_stream)
+            .map(Value::to_string);
+        // With progress the stream is owned by its route, which the
+        // response removes on its way through the router
+        let (tx, rx) = mpsc::unbounded_channel();
+        let tx = match &progress_token {
+            Some(token) => {
+                let stream = RequestStream {
+                    id: serde_json::to_string(&message.id).unwrap_or_default(),
+                    messages: tx,
+                };
+                session
+                    .routes
+                    .progress
+                    .lock()
+                    .unwrap()
+                    .insert(token.clone(), stream);
+                None
+            }
+            None => Some(tx),
+        };
+
+        // The handler runs on its own, so a client dropping the connection
+        // doesn't cancel the request; that takes `notifications/cancelled`
+        let handler = session.server.respond(message);
+        let routes = Arc::clone(&session.routes);
+        let outgoing = session.outgoing.clone();
+        let handler = tokio::spawn(async move {
+            let response = handler.await;
+            let Some(tx) = tx else {
+                match (response, progress_token) {
+                    // Queued behind the request's progress notifications
+                    (Some(response), _) => {
+                        let _ = outgoing.send(response.into());
+                    }
+                    // Cancelled requests are never answered; end the stream
+                    (None, Some(token)) => {
+                        routes.progress.lock().unwrap().remove(&token);
+                    }
+                    (None, None) => {}
+                }
+                return None;
+            };
+            match response {
+                Some(response) if wants_stream => {
+                    let _ = tx.send(response.into());
+                    None
+                }
+                response => response,
+            }
+        });
+
+        if wants_stream {
+            return event_stream(rx);
+        }
+        match handler.await {
+            Ok(Some(response)) => json(StatusCode::OK, &response.into()),
+            // Cancelled requests are never answered
+            _ => status(StatusCode::ACCEPTED, ""),
+        }
+    }
+
+    /// Handles a JSON-RPC batch, answered with a single JSON array once
+    /// every request in it has finished. Progress notifications for its
+    /// requests go to the session's event stream.
+    async fn batch(&self, session: Option<&str>, batch: Vec<Value>) -> Response<Body> {
+        if batch.is_empty() {
+            return json(
+                StatusCode::BAD_REQUEST,
+                &invalid_request(None, "A batch must not be empty").into(),
+            );
+        }
+        let session = match self.find_session(session) {
+            Ok(session) => session,
+            Err((code, message)) => return status(code, message),
+        };
+
+        // As with single requests, a dropped connection doesn't cancel them
+        let handler = session.server.respond_batch(batch).await;
+        match tokio::spawn(handler).await {
+            Ok(responses) if !responses.is_empty() => json(StatusCode::OK, &responses.into()),
+            _ => status(StatusCode::ACCEPTED, ""),
+        }
+    }
+
+    /// Starts a session for `initialize` and returns its id along with the
+    /// response. Sessions whose handshake fails are discarded.
+    async fn initialize(&self, message: JsonRpcRequest) -> Response<Body> {
+        let session = Session::start(&self.base);
+        let Some(response) = session.server.respond(message).await else {
+            session.close();
+            return status(StatusCode::ACCEPTED, "");
+        };
+        if response.error.is_some() {
+            session.close();
+            return json(StatusCode::OK, &response.into());
+        }
+
+        let Ok(id) = new_session_id() else {
+            session.close();
+            return status(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to generate a session id",
+            );
+        };
+        self.sessions
+            .lock()
+            .unwrap()
+            .insert(id.clone(), Arc::clone(&session));
+
+        let mut http_response = json(StatusCode::OK, &response.into());
+        if let Ok(value) = HeaderValue::from_str(&id) {
+            http_response.headers_mut().insert(SESSION_HEADER, value);
+        }
+        http_response
+    }
+
+    /// Opens the stream of server-initiated messages, such as
+    /// `notifications/tools/list_changed`. A new stream replaces the old one.
+    fn get(&self, request: &Request<Incoming>) -> Response<Body> {
+        if !accepts(request, "text/event-stream") {
+            return status(
+                StatusCode::NOT_ACCEPTABLE,
+                "Accept must include text/event-stream",
+            );
+        }
+        let session = match self.find_session(session_id(request).as_deref()) {
+            Ok(session) => session,
+            Err((code, message)) => return status(code, message),
+        };
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        *session.routes.events.lock().unwrap() = Some(tx);
+        event_stream(rx)
+    }
+
+    /// Ends a session at the client's request.
+    fn delete(&self, request: &Request<Incoming>) -> Response<Body> {
+        let Some(id) = session_id(request) else {
+            return status(StatusCode::BAD_REQUEST, "Missing Mcp-Session-Id header");
+        };
+        match self.sessions.lock().unwrap().remove(&id) {
+            Some(session) => {
+                session.close();
+                status(StatusCode::NO_CONTENT, "")
+            }
+            None => status(StatusCode::NOT_FOUND, "Unknown session"),
+        }
+    }
+
+    fn find_session(&self, id: Option<&str>) -> Result<Arc<Session>, (StatusCode, &'static str)> {
+        let id = id.ok_or((StatusCode::BAD_REQUEST, "Missing Mcp-Session-Id header"))?;
+        let session = self
+            .sessions
+            .lock()
+            .unwrap()
+            .get(id)
+            .cloned()
+            // 404 tells the client to start over with a new `initialize`
+            .ok_or((StatusCode::NOT_FOUND, "Unknown session"))?;
+        *session.last_active.lock().unwrap() = Instant::now();
+        Ok(session)
+    }
+
+    fn close_idle_sessions(&self) {
+        self.sessions.lock().unwrap().retain(|_, session| {
+            let idle = session.is_idle(self.idle_timeout);
+            if idle {
+                session.close();
+            }
+            !idle
+        });
+    }
+}
+
+/// Server-sent events carrying one JSON-RPC message each, ending once
+/// every sender is gone.
+struct EventStream {
+    messages: mpsc::UnboundedReceiver<JsonRpcMessage>,
+}
+
+impl hyper::body::Body for EventStream {
+    type Data = Bytes;
+    type Error = Infallible;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, Infallible>>> {
+        self.messages.poll_recv(cx).map(|message| {
+            message.map(|message| {
+                let data = serde_json::to_string(&message).unwrap_or_default();
+                Ok(Frame::data(Bytes::from(format!(
+                    "event: message\ndata: {}\n\n",
+                    data
+                ))))
+            })
+        })
+    }
+}
+
+fn event_stream(messages: mpsc::UnboundedReceiver<JsonRpcMessage>) -> Response<Body> {
+    let mut response = Response::new(EventStream { messages }.boxed());
+    let headers = response.headers_mut();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("text/event-stream"),
+    );
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+    response
+}
+
+fn json(code: StatusCode, message: &JsonRpcMessage) -> Response<Body> {
+    let body = serde_json::to_vec(message).unwrap_or_default();
+    let mut response = Response::new(Full::new(Bytes::from(body)).boxed());
+    *response.status_mut() = code;
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+    response
+}
+
+fn status(code: StatusCode, message: &'static str) -> Response<Body> {
+    let mut response = Response::new(Full::new(Bytes::from_static(message.as_bytes())).boxed());
+    *response.status_mut() = code;
+    response
+}
+
+fn session_id(request: &Request<Incoming>) -> Option<String> {
+    request
+        .headers()
+        .get(SESSION_HEADER)
+        .and_then(|id| id.to_str().ok())
+        .map(str::to_string)
+}
+
+fn accepts(request: &Request<Incoming>, content_type: &str) -> bool {
+    request
+        .headers()
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|accept| accept.to_str().ok())
+        .any(|accept| accept.contains(content_type) || accept.contains("*/*"))
+}
+
+/// Whether `origin` is a page served from this machine.
+pub fn is_local_origin(origin: &str) -> bool {
+    let Some((_, rest)) = origin.split_once("://") else {
+        return false;
+    };
+    let host = rest.split('/').next().unwrap_or_default();
+    let host = match host.strip_prefix('[') {
+        // IPv6 literal, optionally followed by a port
+        Some(v6) => v6.split(']').next().unwrap_or_default(),
+        None => host.split(':').next().unwrap_or_default(),
+    };
+    matches!(host, "localhost" | "127.0.0.1" | "::1")
+}
+
+/// A session id that other clients can't guess: 128 bits from the OS's
+/// random source, hex-encoded.
+fn new_session_id() -> Result<String, getrandom::Error> {
+    let mut bytes = [0u8; 16];
+    getrandom::fill(&mut bytes)?;
+    Ok(bytes.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
@@ -0,0 +1,399 @@
+use crate::angreal::{
+    kill_process_group, run_angreal_command, OutputLine, OutputStream, RunOptions,
+};
+use anyhow::Result;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::AbortHandle;
+
+/// Output kept per job; older lines are dropped once this is exceeded.
+pub const MAX_JOB_OUTPUT_BYTES: usize = 1024 * 1024;
+
+/// Finished jobs kept for `angreal_job_status` and `angreal_job_output`;
+/// the oldest are forgotten when a new job starts. Running jobs are always
+/// kept.
+pub const MAX_FINISHED_JOBS: usize = 20;
+
+/// Angreal tasks running in the background, independent of the request
+/// that started them.
+#[derive(Debug, Default)]
+pub struct JobRegistry {
+    jobs: Mutex<HashMap<String, Arc<Job>>>,
+    next_id: AtomicU64,
+}
+
+impl JobRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts `angreal <command> <args>` in the background, returning as
+    /// soon as the task has a pid or has failed to start. `options` supplies
+    /// the time limit and environment; the output and pid channels are the
+    /// job's own.
+    pub async fn start(&self, command: &str, args: Vec<String>, options: RunOptions) -> Arc<Job> {
+        let id = format!("job-{}", self.next_id.fetch_add(1, Ordering::Relaxed) + 1);
+        let job = Arc::new(Job {
+            id: id.clone(),
+            command: command.to_string(),
+            args: args.clone(),
+            started_at: SystemTime::now(),
+            started: Instant::now(),
+            pid: Mutex::new(None),
+            state: Mutex::new(JobState::Running),
+            finished: Mutex::new(None),
+            output: Mutex::new(OutputBuffer::default()),
+            task: Mutex::new(None),
+        });
+
+        let (line_tx, mut line_rx) = mpsc::unbounded_channel::<OutputLine>();
+        let (pid_tx, mut pid_rx) = mpsc::unbounded_channel::<u32>();
+        // Fired when the pid arrives; dropped unfired if the task ends first
+        let (started_tx, started_rx) = oneshot::channel::<()>();
+        let options = RunOptions {
+            output_lines: Some(line_tx),
+            stream_only: true,
+            pid: Some(pid_tx),
+            ..options
+        };
+
+        let runner = Arc::clone(&job);
+        let command = command.to_string();
+        let task = tokio::spawn(async move {
+            let run = async move { run_angreal_command(&command, &args, &options).await };
+            let collect = async {
+                let mut started_tx = Some(started_tx);
+                loop {
+                    tokio::select! {
+                        Some(pid) = pid_rx.recv() => {
+                            *runner.pid.lock().unwrap() = Some(pid);
+                            if let Some(started_tx) = started_tx.take() {
+                                let _ = started_tx.send(());
+                            }
+                        }
+                        line = line_rx.recv() => match line {
+                            Some(line) => runner.output.lock().unwrap().push(line),
+                            None => break,
+                        },
+                    }
+                }
+            };
+            let (result, ()) = tokio::join!(run, collect);
+
+            let state = match result {
+                Ok(output) if output.timed_out => JobState::TimedOut,
+                Ok(output) => JobState::Exited {
+                    exit_code: output.exit_code(),
+                    signal: output.signal(),
+                },
+                Err(e) => JobState::Failed {
+                    error: e.to_string(),
+                },
+            };
+            runner.finish(state);
+        });
+        *job.task.lock().unwrap() = Some(task.abort_handle());
+
+        {
+            let mut jobs = self.jobs.lock().unwrap();
+            evict_finished(&mut jobs);
+            jobs.insert(id, Arc::clone(&job));
+        }
+        let _ = started_rx.await;
+        job
+    }
+
+    pub fn get(&self, id: &str) -> Option<Arc<Job>> {
+        self.jobs.lock().unwrap().get(id).cloned()
+    }
+
+    pub fn list(&self) -> Vec<Arc<Job>> {
+        let mut jobs: Vec<_> = self.jobs.lock().unwrap().values().cloned().collect();
+        jobs.sort_by_key(|job| job.started);
+        jobs
+    }
+
+    /// Kills every job that is still running.
+    pub fn kill_all(&self) {
+        for job in self.list() {
+            job.kill();
+        }
+    }
+}
+
+/// Forgets the longest-finished jobs beyond [`MAX_FINISHED_JOBS`].
+fn evict_finished(jobs: &mut HashMap<String, Arc<Job>>) {
+    let mut finished: Vec<(Instant, String)> = jobs
+        .values()
+        .filter_map(|job| Some(((*job.finished.lock().unwrap())?, job.id.clone())))
+        .collect();
+    if finished.len() <= MAX_FINISHED_JOBS {
+        return;
+    }
+    finished.sort();
+    let excess = finished.len() - MAX_FINISHED_JOBS;
+    for (_, id) in finished.drain(..excess) {
+        jobs.remove(&id);
+    }
+}
+
+/// How far a job has got.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum JobState {
+    Running,
+    /// The task ended by itself. `signal` is set if a signal ended it.
+    Exited {
+        exit_code: Option<i32>,
+        signal: Option<i32>,
+    },
+    TimedOut,
+    /// Stopped through [`Job::kill`].
+    Killed,
+    /// The task could not be started or waited on.
+    Failed {
+        error: String,
+    },
+}
+
+#[derive(Debug)]
+pub struct Job {
+    pub id: String,
+    pub command: String,
+    pub args: Vec<String>,
+    pub started_at: SystemTime,
+    started: Instant,
+    pid: Mutex<Option<u32>>,
+    state: Mutex<JobState>,
+    /// When the job left the running state.
+    finished: Mutex<Option<Instant>>,
+    output: Mutex<OutputBuffer>,
+    task: Mutex<Option<AbortHandle>>,
+}
+
+impl Job {
+    pub fn state(&self) -> JobState {
+        self.state.lock().unwrap().clone()
+    }
+
+    pub fn pid(&self) -> Option<u32> {
+        *self.pid.lock().unwrap()
+    }
+
+    /// Kills the task along with its process group. Returns `false` if the
+    /// job had already finished.
+    pub fn kill(&self) -> bool {
+        if !self.finish(JobState::Killed) {
+            return false;
+        }
+        if let Some(pid) = self.pid() {
+            kill_process_group(pid);
+        }
+        if let Some(task) = self.task.lock().unwrap().take() {
+            task.abort();
+        }
+        true
+    }
+
+    /// Moves a running job into `state`; later transitions are ignored so
+    /// a kill isn't overwritten by the exit it causes.
+    fn finish(&self, state: JobState) -> bool {
+        let mut current = self.state.lock().unwrap();
+        if *current != JobState::Running {
+            return false;
+        }
+        *current = state;
+        *self.finished.lock().unwrap() = Some(Instant::now());
+        true
+    }
+
+    fn runtime(&self) -> Duration {
+        match *self.finished.lock().unwrap() {
+            Some(finished) => finished - self.started,
+            None => self.started.elapsed(),
+        }
+    }
+
+    /// Everything known about the job, as reported by `angreal_job_status`.
+    pub fn status(&self) -> Value {
+        let output = self.output.lock().unwrap();
+        let mut status = json!({
+            "job_id": self.id,
+            "command": self.command,
+            "args": self.args,
+            "pid": self.pid(),
+            "started_at": self
+                .started_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            "runtime_seconds": self.runtime().as_secs_f64(),
+            "output_lines": output.end_line(),
+            "output_bytes": output.end_byte(),
+        });
+        if let (Value::Object(status), Ok(Value::Object(state))) =
+            (&mut status, serde_json::to_value(self.state()))
+        {
+            status.extend(state);
+        }
+        status
+    }
+
+    /// Reads output starting at `from`, returning at most `limit` lines.
+    pub fn read_output(&self, from: OutputOffset, limit: usize) -> OutputChunk {
+        self.output.lock().unwrap().read(from, limit)
+    }
+}
+
+/// Where to start reading a job's output. Both count from the start of
+/// the job, including output that has since been dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputOffset {
+    Line(u64),
+    Byte(u64),
+}
+
+/// A slice of a job's output plus the offsets to continue from.
+#[derive(Debug, Clone, Serialize)]
+pub struct OutputChunk {
+    /// Output lines; stderr lines are prefixed with `[stderr] `.
+    pub output: String,
+    pub next_line: u64,
+    pub next_byte: u64,
+    /// Lines before the requested offset were dropped to bound memory.
+    pub dropped_lines: u64,
+    /// Whether more output is already available past `next_line`.
+    pub more: bool,
+}
+
+#[derive(Debug)]
+struct BufferedLine {
+    stream: OutputStream,
+    text: String,
+    /// Offset of the line's first byte since the job started.
+    byte: u64,
+}
+
+impl BufferedLine {
+    /// Size including the newline that terminated it.
+    fn len(&self) -> u64 {
+        self.text.len() as u64 + 1
+    }
+}
+
+/// Job output, keeping only the most recent [`MAX_JOB_OUTPUT_BYTES`].
+#[derive(Debug, Default)]
+struct OutputBuffer {
+    lines: VecDeque<BufferedLine>,
+    /// Number of lines dropped from the front.
+    first_line: u64,
+    retained_bytes: usize,
+}
+
+impl OutputBuffer {
+    fn end_line(&self) -> u64 {
+        self.first_line + self.lines.len() as u64
+    }
+
+    fn end_byte(&self) -> u64 {
+        self.lines.back().map_or(0, |line| line.byte + line.len())
+    }
+
+    fn push(&mut self, line: OutputLine) {
+        let byte = self.end_byte();
+        self.retained_bytes += line.text.len() + 1;
+        self.lines.push_back(BufferedLine {
+            stream: line.stream,
+            text: line.text,
+            byte,
+        });
+
+        while self.retained_bytes > MAX_JOB_OUTPUT_BYTES && self.lines.len() > 1 {
+            if let Some(dropped) = self.lines.pop_front() {
+                self.retained_bytes -= dropped.len() as usize;
+                self.first_line += 1;
+            }
+        }
+    }
+
+    fn read(&self, from: OutputOffset, limit: usize) -> OutputChunk {
+        // Index into `lines` to start at, and how much of that line to skip
+        let (start, skip) = match from {
+            OutputOffset::Line(line) => (line.saturating_sub(self.first_line) as usize, 0),
+            OutputOffset::Byte(byte) => {
+                match self
+                    .lines
+                    .iter()
+                    .position(|line| byte < line.byte + line.len())
+                {
+                    Some(index) => {
+                        let line = &self.lines[index];
+                        (index, byte.saturating_sub(line.byte) as usize)
+                    }
+                    None => (self.lines.len(), 0),
+                }
+            }
+        };
+        let requested_line = match from {
+            OutputOffset::Line(line) => line,
+            OutputOffset::Byte(_) => self.first_line + start as u64,
+        };
+
+        let mut output = String::new();
+        let mut end = start;
+        for (index, line) in self.lines.iter().enumerate().skip(start).take(limit) {
+            let mut text = line.text.as_str();
+            if index == start && skip > 0 {
+                let mut cut = skip.min(text.len());
+                while !text.is_char_boundary(cut) {
+                    cut += 1;
+                }
+                text = &text[cut..];
+            }
+            if line.stream == OutputStream::Stderr {
+                output.push_str("[stderr] ");
+            }
+            output.push_str(text);
+            output.push('\n');
+            end = index + 1;
+        }
+        let end = end.max(start.min(self.lines.len()));
+
+        OutputChunk {
+            output,
+            next_line: self.first_line + end as u64,
+            next_byte: self
+                .lines
+                .get(end)
+                .map_or_else(|| self.end_byte(), |line| line.byte),
+            dropped_lines: self.first_line.saturating_sub(requested_line),
+            more: end < self.lines.len(),
+        }
+    }
+}
+
+/// Parses the `offset`/`byte_offset` arguments of `angreal_job_output`.
+pub fn parse_output_offset(arguments: Option<&Value>) -> Result<OutputOffset> {
+    let get = |name: &str| {
+        arguments
+            .and_then(|args| args.get(name))
+            .filter(|v| !v.is_null())
+    };
+    match (get("offset"), get("byte_offset")) {
+        (Some(_), Some(_)) => anyhow::bail!("Pass either 'offset' or 'byte_offset', not both"),
+        (Some(line), None) => line
+            .as_u64()
+            .map(OutputOffset::Line)
+            .ok_or_else(|| anyhow::anyhow!("'offset' must be a non-negative integer")),
+        (None, Some(byte)) => byte
+            .as_u64()
+            .map(OutputOffset::Byte)
+            .ok_or_else(|| anyhow::anyhow!("'byte_offset' must be a non-negative integer")),
+        (None, None) => Ok(OutputOffset::Line(0)),
+    }
+}
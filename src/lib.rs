@@ -1,2 +1,11 @@
 pub mod angreal;
+pub mod http;
+pub mod jobs;
 pub mod mcp;
+#[cfg(unix)]
+mod pty;
+pub mod runs;
+#[cfg(unix)]
+pub mod socket;
+pub mod terminal;
+pub mod transport;
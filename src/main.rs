@@ -1,92 +1,181 @@
 pub mod angreal;
+pub mod http;
+pub mod jobs;
 pub mod mcp;
+#[cfg(unix)]
+mod pty;
+pub mod runs;
+#[cfg(unix)]
+pub mod socket;
+pub mod terminal;
+pub mod transport;
 
-use anyhow::Result;
-use mcp::{JsonRpcRequest, JsonRpcResponse, McpServer};
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use anyhow::{Context, Result};
+use mcp::{McpServer, ServerConfig};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let server = McpServer::new();
-    let stdin = tokio::io::stdin();
-    let mut stdout = tokio::io::stdout();
-    let mut reader = BufReader::new(stdin);
-
-    // Server initialization complete - ready for MCP communication
+/// Command line options.
+struct Args {
+    config: ServerConfig,
+    /// Serve on this address instead of stdio.
+    listen: Option<Listen>,
+}
 
-    let mut line = String::new();
+/// Where to accept clients when not serving stdio.
+enum Listen {
+    /// Streamable HTTP on a loopback TCP address.
+    Http(SocketAddr),
+    /// Newline-delimited JSON on a Unix domain socket.
+    Unix(PathBuf),
+}
 
-    loop {
-        line.clear();
+fn parse_args() -> Result<Args> {
+    let mut config = ServerConfig::default();
+    let mut listen = None;
+    let mut args = std::env::args().skip(1);
 
-        match reader.read_line(&mut line).await {
-            Ok(0) => {
-                // EOF received, shutting down gracefully
-                break;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--timeout" => {
+                let value = args
+                    .next()
+                    .context("--timeout requires a value in seconds")?;
+                let seconds: serde_json::Value = value
+                    .parse::<f64>()
+                    .with_context(|| format!("Invalid --timeout value '{}'", value))?
+                    .into();
+                config.default_timeout = Some(mcp::parse_timeout_seconds(&seconds)?);
             }
-            Ok(_) => {
-                let trimmed = line.trim();
-                if trimmed.is_empty() {
-                    continue;
-                }
+            "--max-output-bytes" => {
+                let value = args.next().context("--max-output-bytes requires a value")?;
+                config.max_output_bytes = value
+                    .parse()
+                    .with_context(|| format!("Invalid --max-output-bytes value '{}'", value))?;
+            }
+            "--color" => config.plain_output = false,
+            "--listen" => {
+                let value = args
+                    .next()
+                    .context("--listen requires an address such as 127.0.0.1:8765")?;
+                listen = Some(match value.strip_prefix("unix:") {
+                    Some("") => anyhow::bail!("--listen unix: requires a socket path"),
+                    Some(path) => Listen::Unix(PathBuf::from(path)),
+                    None => Listen::Http(parse_listen_address(&value)?),
+                });
+            }
+            _ => anyhow::bail!("Unknown argument '{}'", arg),
+        }
+    }
 
-                match serde_json::from_str::<JsonRpcRequest>(trimmed) {
-                    Ok(request) => {
-                        // Process request
-
-                        match server.handle_request(request).await {
-                            Ok(response) => {
-                                let response_str = serde_json::to_string(&response)?;
-                                stdout.write_all(response_str.as_bytes()).await?;
-                                stdout.write_all(b"\n").await?;
-                                stdout.flush().await?;
-                            }
-                            Err(e) => {
-                                // Error handling request
-                                let error_response = JsonRpcResponse {
-                                    jsonrpc: "2.0".to_string(),
-                                    id: None,
-                                    result: None,
-                                    error: Some(mcp::JsonRpcError {
-                                        code: -32603,
-                                        message: "Internal error".to_string(),
-                                        data: Some(serde_json::json!({
-                                            "details": e.to_string()
-                                        })),
-                                    }),
-                                };
-                                let response_str = serde_json::to_string(&error_response)?;
-                                stdout.write_all(response_str.as_bytes()).await?;
-                                stdout.write_all(b"\n").await?;
-                                stdout.flush().await?;
-                            }
-                        }
-                    }
-                    Err(_e) => {
-                        // Failed to parse JSON-RPC request
-                        let error_response = JsonRpcResponse {
-                            jsonrpc: "2.0".to_string(),
-                            id: None,
-                            result: None,
-                            error: Some(mcp::JsonRpcError {
-                                code: -32700,
-                                message: "Parse error".to_string(),
-                                data: None,
-                            }),
-                        };
-                        let response_str = serde_json::to_string(&error_response)?;
-                        stdout.write_all(response_str.as_bytes()).await?;
-                        stdout.write_all(b"\n").await?;
-                        stdout.flush().await?;
-                    }
+    Ok(Args { config, listen })
+}
+
+/// Parses a `host:port` to listen on. The server runs project tasks for
+/// whoever connects, so only loopback addresses are accepted.
+fn parse_listen_address(value: &str) -> Result<SocketAddr> {
+    let address = match value.strip_prefix("localhost:") {
+        Some(port) => SocketAddr::from((
+            Ipv4Addr::LOCALHOST,
+            port.parse()
+                .with_context(|| format!("Invalid port in --listen value '{}'", value))?,
+        )),
+        None => value
+            .parse()
+            .with_context(|| format!("Invalid --listen value '{}'", value))?,
+    };
+    if !address.ip().is_loopback() {
+        anyhow::bail!(
+            "Refusing to listen on {}: only loopback addresses are allowed",
+            address
+        );
+    }
+    Ok(address)
+}
+
+/// Resolves when the process is asked to stop via SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
                 }
             }
-            Err(_e) => {
-                // Error reading from stdin - terminate gracefully
-                break;
+            Err(_) => {
+                let _ = tokio::signal::ctrl_c().await;
             }
         }
     }
 
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = parse_args()?;
+    match args.listen {
+        Some(Listen::Http(address)) => serve_http(args.config, address).await,
+        Some(Listen::Unix(path)) => serve_unix(args.config, path).await,
+        None => serve_stdio(args.config).await,
+    }
+}
+
+/// Serves any number of clients over Streamable HTTP until SIGINT/SIGTERM.
+async fn serve_http(config: ServerConfig, address: SocketAddr) -> Result<()> {
+    let listener = tokio::net::TcpListener::bind(address)
+        .await
+        .with_context(|| format!("Failed to listen on {}", address))?;
+    eprintln!(
+        "angreal_mcp listening on http://{}{}",
+        listener.local_addr()?,
+        http::ENDPOINT
+    );
+
+    let server = Arc::new(http::HttpServer::new(McpServer::with_config(config)));
+    server.serve(listener, shutdown_signal()).await?;
+    Ok(())
+}
+
+/// Serves any number of clients on a Unix domain socket until
+/// SIGINT/SIGTERM, then removes the socket file.
+#[cfg(unix)]
+async fn serve_unix(config: ServerConfig, path: PathBuf) -> Result<()> {
+    let listener = socket::bind(&path)
+        .await
+        .with_context(|| format!("Failed to listen on {}", path.display()))?;
+    eprintln!("angreal_mcp listening on unix:{}", path.display());
+
+    let served = socket::serve(McpServer::with_config(config), listener, shutdown_signal()).await;
+    let _ = std::fs::remove_file(&path);
+    served?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn serve_unix(_config: ServerConfig, _path: PathBuf) -> Result<()> {
+    anyhow::bail!("Unix domain sockets are not supported on this platform")
+}
+
+/// Serves a single client over newline-delimited JSON on stdin/stdout.
+async fn serve_stdio(config: ServerConfig) -> Result<()> {
+    let server = McpServer::with_config(config);
+    let ending = transport::serve(server.session(), transport::stdio(), shutdown_signal()).await;
+    server.kill_jobs();
+
+    if ending? == transport::Ending::Terminated {
+        // The pending blocking read on stdin would keep the runtime from
+        // shutting down, so leave without waiting for it
+        std::process::exit(0);
+    }
+
     Ok(())
 }
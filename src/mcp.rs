@@ -1,6 +1,20 @@
+use crate::angreal::{
+    resolve_working_dir, ArgumentKind, Command, CommandTree, OutputLine, OutputStream, RunOptions,
+    TreeCache,
+};
+use crate::jobs::{parse_output_offset, Job, JobRegistry, MAX_FINISHED_JOBS};
+use crate::runs::{truncate_output, RunStore, DEFAULT_MAX_OUTPUT_BYTES, MAX_STORED_RUNS};
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::MissedTickBehavior;
 
 // Tool descriptions
 const ANGREAL_CHECK_DESC: &str = "Check if the current directory is an angreal project and get project status including available commands
@@ -53,6 +67,58 @@ Success criteria: Command executes successfully with expected output
 Returns:
 Command output including both stdout and stderr for complete results";
 
+const ANGREAL_RUN_OUTPUT_DESC: &str =
+    "Page through or search the full output of an earlier angreal_run call
+
+When to use:
+- An angreal_run result was truncated and you need the omitted part
+- Searching a long test or build log for specific lines, such as errors
+
+Returns:
+Numbered output lines, the total line count, and the offset to continue from";
+
+const ANGREAL_JOB_START_DESC: &str = "Start an angreal command or task in the background and return immediately
+
+When to use:
+- Dev servers, file watchers and other tasks that never exit
+- Test suites or builds that take longer than you want to wait on
+- Running several tasks at once
+
+When NOT to use:
+- For quick commands whose output you need right away (use angreal_run instead)
+
+Prerequisites: Must be in an angreal project with angreal installed
+
+Returns:
+The new job's id and status. Follow up with angreal_job_status, angreal_job_output and angreal_job_kill";
+
+const ANGREAL_JOB_STATUS_DESC: &str = "Get the status of background jobs started with angreal_job_start
+
+When to use:
+- Checking whether a background job is still running or how it exited
+- Listing all jobs when job_id is omitted
+
+Returns:
+Job id, command, pid, start time, runtime, status (running, exited, timed_out, killed, failed), exit code and amount of output";
+
+const ANGREAL_JOB_OUTPUT_DESC: &str = "Read the output of a background job, starting at a line or byte offset
+
+When to use:
+- Tailing a running job: pass back the returned next_line (or next_byte) to get only new output
+- Reading the output of a finished job
+
+Returns:
+Output lines (stderr lines prefixed with [stderr]), the offsets to continue from, and how many lines were dropped because only the most recent output is kept";
+
+const ANGREAL_JOB_KILL_DESC: &str = "Stop a background job and every process it started
+
+When to use:
+- Stopping a dev server or watcher you no longer need
+- Aborting a job that hangs or runs too long
+
+Returns:
+The job's final status";
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct JsonRpcRequest {
     pub jsonrpc: String,
@@ -61,6 +127,13 @@ pub struct JsonRpcRequest {
     pub params: Option<Value>,
 }
 
+impl JsonRpcRequest {
+    /// Messages without an id are notifications and must never be answered.
+    pub fn is_notification(&self) -> bool {
+        self.id.is_none()
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct JsonRpcResponse {
     pub jsonrpc: String,
@@ -71,6 +144,42 @@ pub struct JsonRpcResponse {
     pub error: Option<JsonRpcError>,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JsonRpcNotification {
+    pub jsonrpc: String,
+    pub method: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub params: Option<Value>,
+}
+
+/// Any message the server writes to the client.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum JsonRpcMessage {
+    Response(JsonRpcResponse),
+    Notification(JsonRpcNotification),
+    /// The responses to a batch, written as one array.
+    Batch(Vec<JsonRpcResponse>),
+}
+
+impl From<JsonRpcResponse> for JsonRpcMessage {
+    fn from(response: JsonRpcResponse) -> Self {
+        JsonRpcMessage::Response(response)
+    }
+}
+
+impl From<JsonRpcNotification> for JsonRpcMessage {
+    fn from(notification: JsonRpcNotification) -> Self {
+        JsonRpcMessage::Notification(notification)
+    }
+}
+
+impl From<Vec<JsonRpcResponse>> for JsonRpcMessage {
+    fn from(responses: Vec<JsonRpcResponse>) -> Self {
+        JsonRpcMessage::Batch(responses)
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct JsonRpcError {
     pub code: i32,
@@ -79,18 +188,62 @@ pub struct JsonRpcError {
     pub data: Option<Value>,
 }
 
+/// Protocol revisions this server speaks, newest first.
+pub const SUPPORTED_PROTOCOL_VERSIONS: &[&str] = &["2025-06-18", "2025-03-26", "2024-11-05"];
+
+/// A protocol revision from `SUPPORTED_PROTOCOL_VERSIONS`. Revisions are
+/// dates, so they order correctly as strings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ProtocolVersion(&'static str);
+
+impl ProtocolVersion {
+    pub const LATEST: ProtocolVersion = ProtocolVersion(SUPPORTED_PROTOCOL_VERSIONS[0]);
+    pub const OLDEST: ProtocolVersion =
+        ProtocolVersion(SUPPORTED_PROTOCOL_VERSIONS[SUPPORTED_PROTOCOL_VERSIONS.len() - 1]);
+
+    /// Picks the revision to answer a client requesting `requested` with: the
+    /// highest supported revision not newer than the request, or our latest
+    /// if the client asked for something older than anything we know.
+    pub fn negotiate(requested: &str) -> Self {
+        SUPPORTED_PROTOCOL_VERSIONS
+            .iter()
+            .find(|&&version| version <= requested)
+            .map(|&version| ProtocolVersion(version))
+            .unwrap_or(Self::LATEST)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        self.0
+    }
+
+    /// Tool `annotations` (read-only/destructive hints) arrived in 2025-03-26.
+    pub fn supports_tool_annotations(&self) -> bool {
+        self.0 >= "2025-03-26"
+    }
+
+    /// Tool `title`, `outputSchema` and `structuredContent` arrived in 2025-06-18.
+    pub fn supports_structured_content(&self) -> bool {
+        self.0 >= "2025-06-18"
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct InitializeParams {
     pub protocol_version: String,
+    #[serde(default)]
     pub capabilities: ClientCapabilities,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_info: Option<Value>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct ClientCapabilities {
     pub tools: Option<ToolsCapability>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct ToolsCapability {
     pub call_tool: Option<bool>,
 }
@@ -102,6 +255,7 @@ pub struct ServerCapabilities {
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct ServerToolsCapability {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub list_changed: Option<bool>,
@@ -110,34 +264,119 @@ pub struct ServerToolsCapability {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Tool {
     pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
     pub description: String,
     #[serde(rename = "inputSchema")]
     pub input_schema: Value,
+    #[serde(rename = "outputSchema", skip_serializing_if = "Option::is_none")]
+    pub output_schema: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub annotations: Option<Value>,
+}
+
+impl Tool {
+    /// Returns the tool as advertised to a client speaking `version`,
+    /// dropping fields that revision doesn't define.
+    pub fn for_version(&self, version: ProtocolVersion) -> Tool {
+        let mut tool = self.clone();
+        if !version.supports_tool_annotations() {
+            tool.annotations = None;
+        }
+        if !version.supports_structured_content() {
+            tool.title = None;
+            tool.output_schema = None;
+        }
+        tool
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ToolCallParams {
     pub name: String,
     pub arguments: Option<Value>,
+    #[serde(rename = "_meta", default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<Value>,
+}
+
+/// Where a session is in the MCP lifecycle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionState {
+    /// Waiting for `initialize`; only `ping` is accepted besides it.
+    Uninitialized,
+    /// `initialize` was answered, `notifications/initialized` not yet seen.
+    Initializing,
+    /// Normal operation.
+    Ready,
+    /// The client went away; in-flight requests finish, new ones are refused.
+    ShuttingDown,
+}
+
+/// Server-wide settings, fixed for the lifetime of the process.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Time limit applied to `angreal_run` calls that don't pass
+    /// `timeout_seconds` themselves. `None` means no limit.
+    pub default_timeout: Option<Duration>,
+    /// Size each output stream of a run is truncated to, unless the call
+    /// passes `max_output_bytes`. 0 means no limit.
+    pub max_output_bytes: usize,
+    /// Set `NO_COLOR=1` and `TERM=dumb` for tasks so they print plain text.
+    pub plain_output: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            default_timeout: None,
+            max_output_bytes: DEFAULT_MAX_OUTPUT_BYTES,
+            plain_output: true,
+        }
+    }
 }
 
 pub struct McpServer {
     tools: Vec<Tool>,
+    config: ServerConfig,
+    tree_cache: Arc<TreeCache>,
+    jobs: Arc<JobRegistry>,
+    runs: Arc<RunStore>,
+    /// The tool list the client last saw, to tell when it has gone stale.
+    listed_tools: Mutex<Option<Value>>,
+    /// Cancellation triggers for requests currently being handled, keyed by
+    /// the JSON encoding of their id.
+    in_flight: Mutex<HashMap<String, oneshot::Sender<()>>>,
+    /// Channel for server-initiated messages such as progress notifications.
+    notifier: Option<mpsc::UnboundedSender<JsonRpcMessage>>,
+    /// Revision agreed on during `initialize`.
+    protocol_version: Mutex<Option<ProtocolVersion>>,
+    state: Mutex<SessionState>,
 }
 
 impl McpServer {
     pub fn new() -> Self {
+        Self::with_config(ServerConfig::default())
+    }
+
+    pub fn with_config(config: ServerConfig) -> Self {
         let angreal_check_tool = Tool {
             name: "angreal_check".to_string(),
+            title: Some("Check angreal project".to_string()),
             description: ANGREAL_CHECK_DESC.to_string(),
             input_schema: json!({
                 "type": "object",
                 "properties": {}
             }),
+            output_schema: None,
+            annotations: Some(json!({
+                "readOnlyHint": true,
+                "openWorldHint": false
+            })),
         };
 
         let angreal_tree_tool = Tool {
             name: "angreal_tree".to_string(),
+            title: Some("List angreal commands".to_string()),
             description: ANGREAL_TREE_DESC.to_string(),
             input_schema: json!({
                 "type": "object",
@@ -150,10 +389,16 @@ impl McpServer {
                     }
                 }
             }),
+            output_schema: None,
+            annotations: Some(json!({
+                "readOnlyHint": true,
+                "openWorldHint": false
+            })),
         };
 
         let angreal_run_tool = Tool {
             name: "angreal_run".to_string(),
+            title: Some("Run angreal task".to_string()),
             description: ANGREAL_RUN_DESC.to_string(),
             input_schema: json!({
                 "type": "object",
@@ -169,27 +414,577 @@ impl McpServer {
                         "description": "Additional arguments, options, and flags to pass to the command. Each argument should be a separate array element for proper shell safety.",
                         "examples": [["--release"], ["--env", "production"], ["--var", "name=value"]],
                         "default": []
+                    },
+                    "timeout_seconds": {
+                        "type": "number",
+                        "exclusiveMinimum": 0,
+                        "description": "Maximum time the task may run. When exceeded the task and all processes it started are killed and the partial output is returned. Defaults to the server-wide timeout, if one is configured."
+                    },
+                    "max_output_bytes": {
+                        "type": "integer",
+                        "minimum": 0,
+                        "description": "Longer stdout or stderr is cut down to its head and tail, with a marker in between; the full output stays available through angreal_run_output. 0 disables truncation. Defaults to the server-wide limit."
+                    },
+                    "env": {
+                        "type": "object",
+                        "additionalProperties": {"type": "string"},
+                        "description": "Extra environment variables for the task, on top of the server's environment.",
+                        "examples": [{"RUST_LOG": "debug"}]
+                    },
+                    "cwd": {
+                        "type": "string",
+                        "description": "Directory to run the task in, e.g. a sub-package. Relative paths are resolved against the server's working directory, and the directory must be inside the angreal project."
+                    },
+                    "stdin": {
+                        "type": "string",
+                        "description": "Input for the task, e.g. answers to its prompts, one per line. The input is closed afterwards. Without it the task gets no input at all, so a prompt fails right away instead of waiting."
+                    },
+                    "pty": {
+                        "type": "boolean",
+                        "description": "Run the task attached to a pseudo-terminal instead of pipes. Only needed for tasks that misbehave or refuse to run without a TTY. Stdout and stderr are combined into stdout.",
+                        "default": false
+                    }
+                },
+                "required": ["command"]
+            }),
+            output_schema: Some(run_output_schema()),
+            // Tasks are arbitrary project code, so assume the worst
+            annotations: Some(run_annotations()),
+        };
+
+        let angreal_run_output_tool = Tool {
+            name: "angreal_run_output".to_string(),
+            title: Some("Read angreal run output".to_string()),
+            description: ANGREAL_RUN_OUTPUT_DESC.to_string(),
+            input_schema: json!({
+                "type": "object",
+                "properties": {
+                    "run_id": {
+                        "type": "string",
+                        "description": "The run_id reported by angreal_run"
+                    },
+                    "stream": {
+                        "type": "string",
+                        "enum": ["stdout", "stderr"],
+                        "default": "stdout",
+                        "description": "Which output stream to read."
+                    },
+                    "offset": {
+                        "type": "integer",
+                        "minimum": 0,
+                        "default": 0,
+                        "description": "Line to start at (0-based). With grep, the number of matches to skip."
+                    },
+                    "limit": {
+                        "type": "integer",
+                        "minimum": 1,
+                        "default": DEFAULT_OUTPUT_PAGE_LINES,
+                        "description": "Maximum number of lines to return."
+                    },
+                    "grep": {
+                        "type": "string",
+                        "description": "Only return lines containing this text."
+                    }
+                },
+                "required": ["run_id"]
+            }),
+            output_schema: None,
+            annotations: Some(json!({
+                "readOnlyHint": true,
+                "openWorldHint": false
+            })),
+        };
+
+        let job_id_schema = json!({
+            "type": "string",
+            "description": "Id returned by angreal_job_start"
+        });
+
+        let angreal_job_start_tool = Tool {
+            name: "angreal_job_start".to_string(),
+            title: Some("Start background angreal task".to_string()),
+            description: ANGREAL_JOB_START_DESC.to_string(),
+            input_schema: json!({
+                "type": "object",
+                "properties": {
+                    "command": {
+                        "type": "string",
+                        "description": "The angreal command/task to execute, as for angreal_run."
+                    },
+                    "args": {
+                        "type": "array",
+                        "items": {"type": "string"},
+                        "description": "Additional arguments, options, and flags to pass to the command.",
+                        "default": []
+                    },
+                    "timeout_seconds": {
+                        "type": "number",
+                        "exclusiveMinimum": 0,
+                        "description": "Kill the job after this many seconds. Background jobs run without a limit by default."
                     }
                 },
                 "required": ["command"]
             }),
+            output_schema: None,
+            annotations: Some(run_annotations()),
+        };
+
+        let angreal_job_status_tool = Tool {
+            name: "angreal_job_status".to_string(),
+            title: Some("Background job status".to_string()),
+            description: ANGREAL_JOB_STATUS_DESC.to_string(),
+            input_schema: json!({
+                "type": "object",
+                "properties": {
+                    "job_id": job_id_schema
+                }
+            }),
+            output_schema: None,
+            annotations: Some(json!({
+                "readOnlyHint": true,
+                "openWorldHint": false
+            })),
+        };
+
+        let angreal_job_output_tool = Tool {
+            name: "angreal_job_output".to_string(),
+            title: Some("Background job output".to_string()),
+            description: ANGREAL_JOB_OUTPUT_DESC.to_string(),
+            input_schema: json!({
+                "type": "object",
+                "properties": {
+                    "job_id": job_id_schema,
+                    "offset": {
+                        "type": "integer",
+                        "minimum": 0,
+                        "description": "Line to start reading at, counted from the start of the job. Defaults to 0."
+                    },
+                    "byte_offset": {
+                        "type": "integer",
+                        "minimum": 0,
+                        "description": "Byte to start reading at instead of a line. Cannot be combined with offset."
+                    },
+                    "limit": {
+                        "type": "integer",
+                        "minimum": 1,
+                        "default": DEFAULT_JOB_OUTPUT_LINES,
+                        "description": "Maximum number of lines to return."
+                    }
+                },
+                "required": ["job_id"]
+            }),
+            output_schema: None,
+            annotations: Some(json!({
+                "readOnlyHint": true,
+                "openWorldHint": false
+            })),
+        };
+
+        let angreal_job_kill_tool = Tool {
+            name: "angreal_job_kill".to_string(),
+            title: Some("Kill background job".to_string()),
+            description: ANGREAL_JOB_KILL_DESC.to_string(),
+            input_schema: json!({
+                "type": "object",
+                "properties": {
+                    "job_id": job_id_schema
+                },
+                "required": ["job_id"]
+            }),
+            output_schema: None,
+            annotations: Some(json!({
+                "readOnlyHint": false,
+                "destructiveHint": true,
+                "idempotentHint": true,
+                "openWorldHint": false
+            })),
         };
 
         Self {
-            tools: vec![angreal_check_tool, angreal_tree_tool, angreal_run_tool],
+            tools: vec![
+                angreal_check_tool,
+                angreal_tree_tool,
+                angreal_run_tool,
+                angreal_run_output_tool,
+                angreal_job_start_tool,
+                angreal_job_status_tool,
+                angreal_job_output_tool,
+                angreal_job_kill_tool,
+            ],
+            config,
+            tree_cache: Arc::new(TreeCache::new()),
+            jobs: Arc::new(JobRegistry::new()),
+            runs: Arc::new(RunStore::new()),
+            listed_tools: Mutex::new(None),
+            in_flight: Mutex::new(HashMap::new()),
+            notifier: None,
+            protocol_version: Mutex::new(None),
+            state: Mutex::new(SessionState::Uninitialized),
+        }
+    }
+
+    /// A new session for another client of the same server process. It
+    /// shares the configuration, tree cache, background jobs and stored runs
+    /// but goes through its own handshake.
+    pub fn session(&self) -> Self {
+        Self {
+            tools: self.tools.clone(),
+            config: self.config.clone(),
+            tree_cache: Arc::clone(&self.tree_cache),
+            jobs: Arc::clone(&self.jobs),
+            runs: Arc::clone(&self.runs),
+            listed_tools: Mutex::new(None),
+            in_flight: Mutex::new(HashMap::new()),
+            notifier: None,
+            protocol_version: Mutex::new(None),
+            state: Mutex::new(SessionState::Uninitialized),
+        }
+    }
+
+    pub fn state(&self) -> SessionState {
+        *self.state.lock().unwrap()
+    }
+
+    /// Stops accepting new requests. Requests already running are left to
+    /// finish; the transport drains them before exiting.
+    pub fn begin_shutdown(&self) {
+        *self.state.lock().unwrap() = SessionState::ShuttingDown;
+    }
+
+    /// Kills all background jobs started through this server.
+    pub fn kill_jobs(&self) {
+        self.jobs.kill_all();
+    }
+
+    /// Whether any request is still being handled.
+    pub fn has_requests_in_flight(&self) -> bool {
+        !self.in_flight.lock().unwrap().is_empty()
+    }
+
+    /// Cancels every in-flight request, killing the tasks they started.
+    pub fn cancel_all(&self) {
+        for (_, cancel) in self.in_flight.lock().unwrap().drain() {
+            let _ = cancel.send(());
+        }
+    }
+
+    /// The negotiated protocol revision, or the oldest supported one if the
+    /// client hasn't initialized yet.
+    pub fn protocol_version(&self) -> ProtocolVersion {
+        self.protocol_version
+            .lock()
+            .unwrap()
+            .unwrap_or(ProtocolVersion::OLDEST)
+    }
+
+    /// Loads command trees through `cache` instead of a fresh one, e.g. to
+    /// serve a project other than the current directory's.
+    pub fn with_tree_cache(mut self, cache: TreeCache) -> Self {
+        self.tree_cache = Arc::new(cache);
+        self
+    }
+
+    /// Sends server-initiated notifications to `notifier`. Without one the
+    /// server never emits notifications.
+    pub fn with_notifier(mut self, notifier: mpsc::UnboundedSender<JsonRpcMessage>) -> Self {
+        self.notifier = Some(notifier);
+        self
+    }
+
+    fn notify(&self, method: &str, params: Option<Value>) {
+        if let Some(notifier) = &self.notifier {
+            let _ = notifier.send(
+                JsonRpcNotification {
+                    jsonrpc: "2.0".to_string(),
+                    method: method.to_string(),
+                    params,
+                }
+                .into(),
+            );
+        }
+    }
+
+    /// Checks the project's tasks every `interval` and sends
+    /// `notifications/tools/list_changed` once the tool list differs from
+    /// the one the client last saw. Stops when the server is dropped.
+    pub fn watch_tools(self: &Arc<Self>, interval: Duration) -> JoinHandle<()> {
+        let server = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                ticks.tick().await;
+                let Some(server) = server.upgrade() else {
+                    return;
+                };
+                server.check_tools_changed().await;
+            }
+        })
+    }
+
+    async fn check_tools_changed(&self) {
+        if self.state() != SessionState::Ready {
+            return;
+        }
+
+        let Ok(current) = serde_json::to_value(self.current_tools().await) else {
+            return;
+        };
+        let changed = {
+            let mut listed = self.listed_tools.lock().unwrap();
+            let changed = listed.as_ref().is_some_and(|listed| *listed != current);
+            *listed = Some(current);
+            changed
+        };
+        if changed {
+            self.notify("notifications/tools/list_changed", None);
+        }
+    }
+
+    /// Forwards task output as `notifications/progress` until `lines` closes.
+    async fn report_progress(
+        &self,
+        token: Option<Value>,
+        lines: Option<mpsc::UnboundedReceiver<OutputLine>>,
+    ) {
+        let (Some(token), Some(mut lines)) = (token, lines) else {
+            return;
+        };
+
+        let mut progress = 0u64;
+        while let Some(line) = lines.recv().await {
+            progress += 1;
+            let message = match line.stream {
+                OutputStream::Stdout => line.text,
+                OutputStream::Stderr => format!("[stderr] {}", line.text),
+            };
+            self.notify(
+                "notifications/progress",
+                Some(json!({
+                    "progressToken": token,
+                    "progress": progress,
+                    "message": message
+                })),
+            );
+        }
+    }
+
+    /// Registers `request` as in flight and returns a future that handles it.
+    ///
+    /// Registration happens before this returns, so a `notifications/cancelled`
+    /// read right after the request always finds it. The future resolves to
+    /// `None` if the request was cancelled; dropping the handler on the way
+    /// out kills any angreal task it had started.
+    pub fn handle_cancellable(
+        self: &Arc<Self>,
+        request: JsonRpcRequest,
+    ) -> impl Future<Output = Option<Result<JsonRpcResponse>>> + Send + 'static {
+        let server = Arc::clone(self);
+        // Admission is decided now, in arrival order, not when the handler
+        // first gets polled
+        let rejection = self.admit(&request);
+        let registration = request.id.as_ref().map(|id| {
+            let key = id.to_string();
+            let (tx, rx) = oneshot::channel();
+            server.in_flight.lock().unwrap().insert(key.clone(), tx);
+            (key, rx)
+        });
+
+        async move {
+            if let Some(rejection) = rejection {
+                if let Some((key, _)) = registration {
+                    server.in_flight.lock().unwrap().remove(&key);
+                }
+                return Some(Ok(rejection));
+            }
+            let Some((key, cancelled)) = registration else {
+                return Some(server.dispatch(request).await);
+            };
+
+            let result = tokio::select! {
+                result = server.dispatch(request) => Some(result),
+                Ok(()) = cancelled => None,
+            };
+            server.in_flight.lock().unwrap().remove(&key);
+            result
+        }
+    }
+
+    /// Like [`handle_cancellable`](Self::handle_cancellable), but turns a
+    /// failed handler into an internal error response, ready to be sent.
+    pub fn respond(
+        self: &Arc<Self>,
+        request: JsonRpcRequest,
+    ) -> impl Future<Output = Option<JsonRpcResponse>> + Send + 'static {
+        let id = request.id.clone();
+        let handler = self.handle_cancellable(request);
+        async move {
+            match handler.await? {
+                Ok(response) => Some(response),
+                Err(e) => Some(JsonRpcResponse {
+                    jsonrpc: "2.0".to_string(),
+                    id,
+                    result: None,
+                    error: Some(JsonRpcError {
+                        code: -32603,
+                        message: "Internal error".to_string(),
+                        data: Some(json!({
+                            "details": e.to_string()
+                        })),
+                    }),
+                }),
+            }
+        }
+    }
+
+    /// Starts on a JSON-RPC batch. Its messages are taken in order just as if
+    /// they had arrived one at a time: notifications are handled and
+    /// `initialize` answered before this returns, and every other request
+    /// is admitted, then left running concurrently with the rest.
+    ///
+    /// The returned future resolves to the batch's responses in the order of
+    /// their requests. Notifications, cancelled requests and responses from
+    /// the client get none, so it may be empty, in which case nothing should
+    /// be sent at all.
+    pub async fn respond_batch(
+        self: &Arc<Self>,
+        batch: Vec<Value>,
+    ) -> impl Future<Output = Vec<JsonRpcResponse>> + Send + 'static {
+        let mut responses = Vec::new();
+        let mut handlers = JoinSet::new();
+
+        for (index, message) in batch.into_iter().enumerate() {
+            if is_client_response(&message) {
+                continue;
+            }
+            let request = match parse_request(message) {
+                Ok(request) => request,
+                Err(rejection) => {
+                    responses.push((index, rejection.response()));
+                    continue;
+                }
+            };
+
+            if request.is_notification() {
+                self.handle_notification(request);
+            } else if request.method == "initialize" {
+                // Requests later in the batch must see the session initialized
+                if let Some(response) = self.respond(request).await {
+                    responses.push((index, response));
+                }
+            } else {
+                let handler = self.respond(request);
+                handlers.spawn(async move { (index, handler.await) });
+            }
+        }
+
+        async move {
+            while let Some(finished) = handlers.join_next().await {
+                if let Ok((index, Some(response))) = finished {
+                    responses.push((index, response));
+                }
+            }
+            responses.sort_by_key(|(index, _)| *index);
+            responses
+                .into_iter()
+                .map(|(_, response)| response)
+                .collect()
+        }
+    }
+
+    /// Dispatches a client notification. Notifications never produce a
+    /// response, and unknown ones are ignored as JSON-RPC requires.
+    pub fn handle_notification(&self, notification: JsonRpcRequest) {
+        match notification.method.as_str() {
+            "notifications/cancelled" => self.handle_cancelled(notification.params),
+            "notifications/initialized" => {
+                let mut state = self.state.lock().unwrap();
+                if *state == SessionState::Initializing {
+                    *state = SessionState::Ready;
+                }
+            }
+            // The server works on its launch directory and never asks for
+            // roots, so a changed root list doesn't affect it.
+            "notifications/roots/list_changed" => {}
+            _ => {}
+        }
+    }
+
+    /// Handles `notifications/cancelled`, aborting the referenced request if
+    /// it is still running. Unknown or finished ids are ignored, as the spec
+    /// requires.
+    pub fn handle_cancelled(&self, params: Option<Value>) {
+        let Some(request_id) = params.as_ref().and_then(|p| p.get("requestId")) else {
+            return;
+        };
+
+        if let Some(cancel) = self
+            .in_flight
+            .lock()
+            .unwrap()
+            .remove(&request_id.to_string())
+        {
+            let _ = cancel.send(());
         }
     }
 
     pub async fn handle_request(&self, request: JsonRpcRequest) -> Result<JsonRpcResponse> {
+        match self.admit(&request) {
+            Some(rejection) => Ok(rejection),
+            None => self.dispatch(request).await,
+        }
+    }
+
+    /// Checks `request` against the session lifecycle, returning the error
+    /// response if it isn't allowed right now. `ping` and `initialize` are
+    /// valid before the handshake; everything else needs an initialized
+    /// session that isn't shutting down.
+    fn admit(&self, request: &JsonRpcRequest) -> Option<JsonRpcResponse> {
+        if matches!(request.method.as_str(), "ping" | "initialize") {
+            return None;
+        }
+
+        let (code, message) = match self.state() {
+            SessionState::Uninitialized => (-32002, "Server not initialized"),
+            SessionState::ShuttingDown => (-32000, "Server is shutting down"),
+            SessionState::Initializing | SessionState::Ready => return None,
+        };
+        Some(JsonRpcResponse {
+            jsonrpc: "2.0".to_string(),
+            id: request.id.clone(),
+            result: None,
+            error: Some(JsonRpcError {
+                code,
+                message: message.to_string(),
+                data: None,
+            }),
+        })
+    }
+
+    /// Routes `request` to its handler. Invalid params reported as errors
+    /// by the handler are answered like any other invalid params.
+    async fn dispatch(&self, request: JsonRpcRequest) -> Result<JsonRpcResponse> {
+        let id = request.id.clone();
+        self.route(request)
+            .await
+            .or_else(|e| match e.downcast::<InvalidParams>() {
+                Ok(invalid) => Ok(invalid.response(id)),
+                Err(e) => Err(e),
+            })
+    }
+
+    async fn route(&self, request: JsonRpcRequest) -> Result<JsonRpcResponse> {
         match request.method.as_str() {
-            "initialize" => self.handle_initialize(request.id).await,
+            "ping" => Ok(JsonRpcResponse {
+                jsonrpc: "2.0".to_string(),
+                id: request.id,
+                result: Some(json!({})),
+                error: None,
+            }),
+            "initialize" => self.handle_initialize(request.id, request.params).await,
             "tools/list" => self.handle_tools_list(request.id).await,
             "tools/call" => {
-                let params: ToolCallParams = serde_json::from_value(
-                    request
-                        .params
-                        .ok_or_else(|| anyhow::anyhow!("Missing params"))?,
-                )?;
+                let params = parse_tool_call_params(request.params)?;
                 self.handle_tool_call(request.id, params).await
             }
             _ => Ok(JsonRpcResponse {
@@ -205,103 +1000,82 @@ impl McpServer {
         }
     }
 
-    async fn handle_initialize(&self, id: Option<Value>) -> Result<JsonRpcResponse> {
+    async fn handle_initialize(
+        &self,
+        id: Option<Value>,
+        params: Option<Value>,
+    ) -> Result<JsonRpcResponse> {
+        let params: InitializeParams = match params.map(serde_json::from_value).transpose() {
+            Ok(Some(params)) => params,
+            Ok(None) | Err(_) => {
+                return Ok(JsonRpcResponse {
+                    jsonrpc: "2.0".to_string(),
+                    id,
+                    result: None,
+                    error: Some(JsonRpcError {
+                        code: -32602,
+                        message: "Invalid params".to_string(),
+                        data: Some(json!({
+                            "details": "initialize requires params with a 'protocolVersion' string",
+                            "pointer": "/protocolVersion",
+                            "supportedVersions": SUPPORTED_PROTOCOL_VERSIONS,
+                        })),
+                    }),
+                });
+            }
+        };
+
+        {
+            let mut state = self.state.lock().unwrap();
+            if *state != SessionState::Uninitialized {
+                return Ok(JsonRpcResponse {
+                    jsonrpc: "2.0".to_string(),
+                    id,
+                    result: None,
+                    error: Some(JsonRpcError {
+                        code: -32600,
+                        message: "Invalid Request".to_string(),
+                        data: Some(json!({
+                            "details": "initialize may only be sent once per session",
+                        })),
+                    }),
+                });
+            }
+            *state = SessionState::Initializing;
+        }
+
+        let protocol_version = ProtocolVersion::negotiate(&params.protocol_version);
+        *self.protocol_version.lock().unwrap() = Some(protocol_version);
+
         let capabilities = ServerCapabilities {
             tools: Some(ServerToolsCapability {
-                list_changed: Some(false),
+                list_changed: Some(true),
             }),
         };
 
         // Check project status during initialization
-        let project_status = match crate::angreal::check_angreal_project_status().await {
-            Ok(status) => status,
-            Err(_) => "Unable to determine project status".to_string(),
-        };
+        let project_status =
+            match crate::angreal::check_angreal_project_status(&self.tree_cache).await {
+                Ok(status) => status,
+                Err(_) => "Unable to determine project status".to_string(),
+            };
 
-        let is_angreal_project = std::path::Path::new(".angreal").exists();
+        let is_angreal_project = self.tree_cache.project_root().is_some();
         let current_dir = std::env::current_dir()
             .map(|d| d.display().to_string())
             .unwrap_or_else(|_| "unknown".to_string());
 
-        // Extract available commands with full command strings using new format
+        // Extract available commands with their full usage lines, keeping
+        // each group's commands together
         let available_commands = if is_angreal_project {
-            match crate::angreal::get_angreal_tree("json").await {
-                Ok(tree_json) => {
-                    if let Ok(parsed) = serde_json::from_str::<Value>(&tree_json) {
-                        if let Some(commands) = parsed.get("commands").and_then(|c| c.as_array()) {
-                            commands
-                                .iter()
-                                .map(|cmd| {
-                                    let _name =
-                                        cmd.get("name").and_then(|n| n.as_str()).unwrap_or("");
-                                    let path =
-                                        cmd.get("path").and_then(|p| p.as_str()).unwrap_or("");
-                                    let description = cmd
-                                        .get("description")
-                                        .and_then(|d| d.as_str())
-                                        .unwrap_or("");
-
-                                    // Extract argument info
-                                    let mut args_info = String::new();
-                                    if let Some(arguments) =
-                                        cmd.get("arguments").and_then(|a| a.as_array())
-                                    {
-                                        let mut formatted_args = Vec::new();
-                                        for arg in arguments {
-                                            let flag = arg
-                                                .get("flag")
-                                                .and_then(|f| f.as_str())
-                                                .unwrap_or("");
-                                            let arg_type = arg
-                                                .get("type")
-                                                .and_then(|t| t.as_str())
-                                                .unwrap_or("");
-                                            let required = arg
-                                                .get("required")
-                                                .and_then(|r| r.as_bool())
-                                                .unwrap_or(false);
-
-                                            let formatted = match arg_type {
-                                                "flag" => flag.to_string(),
-                                                "parameter" => format!("{} <value>", flag),
-                                                "positional" => {
-                                                    // If positional has a flag (like --parameter), treat as parameter
-                                                    if !flag.is_empty() && flag.starts_with("--") {
-                                                        format!("{} <value>", flag)
-                                                    } else {
-                                                        // True positional argument
-                                                        format!(
-                                                            "<{}>",
-                                                            arg.get("name")
-                                                                .and_then(|n| n.as_str())
-                                                                .unwrap_or("arg")
-                                                        )
-                                                    }
-                                                }
-                                                _ => flag.to_string(),
-                                            };
-
-                                            if required {
-                                                formatted_args.push(formatted);
-                                            } else {
-                                                formatted_args.push(format!("[{}]", formatted));
-                                            }
-                                        }
-                                        if !formatted_args.is_empty() {
-                                            args_info = format!(" {}", formatted_args.join(" "));
-                                        }
-                                    }
-
-                                    format!("{}{} - {}", path, args_info, description)
-                                })
-                                .collect::<Vec<_>>()
-                        } else {
-                            vec![]
-                        }
-                    } else {
-                        vec![]
-                    }
-                }
+            match self.tree_cache.load().await {
+                Ok(snapshot) => snapshot
+                    .tree
+                    .groups()
+                    .into_iter()
+                    .flat_map(|(_, commands)| commands)
+                    .map(|cmd| format!("{} - {}", cmd.signature(), cmd.description))
+                    .collect::<Vec<_>>(),
                 Err(_) => vec![],
             }
         } else {
@@ -328,7 +1102,7 @@ impl McpServer {
             jsonrpc: "2.0".to_string(),
             id,
             result: Some(json!({
-                "protocolVersion": "2024-11-05",
+                "protocolVersion": protocol_version.as_str(),
                 "capabilities": capabilities,
                 "serverInfo": {
                     "name": server_name,
@@ -347,97 +1121,298 @@ impl McpServer {
     }
 
     async fn handle_tools_list(&self, id: Option<Value>) -> Result<JsonRpcResponse> {
+        let tools = self.current_tools().await;
+        *self.listed_tools.lock().unwrap() = Some(serde_json::to_value(&tools)?);
+
         Ok(JsonRpcResponse {
             jsonrpc: "2.0".to_string(),
             id,
             result: Some(json!({
-                "tools": self.tools,
+                "tools": tools,
             })),
             error: None,
         })
     }
 
+    /// The built-in tools plus one per project command, shaped for the
+    /// negotiated protocol version.
+    async fn current_tools(&self) -> Vec<Tool> {
+        let version = self.protocol_version();
+        let mut tools = self.tools.clone();
+
+        // Outside a working angreal project only the built-in tools exist
+        if self.tree_cache.project_root().is_some() {
+            if let Ok(snapshot) = self.tree_cache.load().await {
+                tools.extend(task_tools(&snapshot.tree));
+            }
+        }
+
+        tools.iter().map(|tool| tool.for_version(version)).collect()
+    }
+
+    /// Options for running a task with `timeout`, filled in from the
+    /// server's configuration.
+    fn run_options(&self, timeout: Option<Duration>) -> RunOptions {
+        RunOptions {
+            timeout,
+            plain_output: self.config.plain_output,
+            ..RunOptions::default()
+        }
+    }
+
+    /// Runs `angreal <command> <args>` and turns the outcome into a
+    /// `tools/call` response, streaming progress if `meta` asks for it.
+    async fn run_angreal_task(
+        &self,
+        id: Option<Value>,
+        command: &str,
+        args: Vec<String>,
+        options: RunOptions,
+        max_output_bytes: usize,
+        meta: Option<&Value>,
+    ) -> Result<JsonRpcResponse> {
+        // Stream output lines as progress when the client asked for it
+        let progress_token = meta.and_then(|meta| meta.get("progressToken")).cloned();
+        let (output_lines, progress_lines) = if progress_token.is_some() && self.notifier.is_some()
+        {
+            let (tx, rx) = mpsc::unbounded_channel();
+            (Some(tx), Some(rx))
+        } else {
+            (None, None)
+        };
+        let timeout = options.timeout;
+        let had_input = options.stdin.is_some();
+        let options = RunOptions {
+            output_lines,
+            ..options
+        };
+
+        // `options` is dropped once the task finishes, which closes the
+        // line channel and lets the progress reporter drain and return
+        // before the response is sent.
+        let (result, ()) = tokio::join!(
+            async move { crate::angreal::run_angreal_command(command, &args, &options).await },
+            self.report_progress(progress_token, progress_lines),
+        );
+
+        // Keep the full output for angreal_run_output and only hand the
+        // head and tail of long streams to the client
+        let mut result = result;
+        let mut structured = None;
+        if let Ok(output) = &mut result {
+            let run = self.runs.store(
+                output.argv.clone(),
+                std::mem::take(&mut output.stdout),
+                std::mem::take(&mut output.stderr),
+            );
+            let stdout_truncated;
+            let stderr_truncated;
+            (output.stdout, stdout_truncated) =
+                truncate_output(&run, OutputStream::Stdout, max_output_bytes);
+            (output.stderr, stderr_truncated) =
+                truncate_output(&run, OutputStream::Stderr, max_output_bytes);
+
+            // Clients that understand structured results get the streams
+            // and exit status alongside the text
+            if self.protocol_version().supports_structured_content() {
+                let mut content = structured_run_output(output);
+                content["run_id"] = json!(run.id);
+                content["stdout_truncated"] = json!(stdout_truncated);
+                content["stderr_truncated"] = json!(stderr_truncated);
+                structured = Some(content);
+            }
+        }
+
+        let ran_out_of_input = matches!(&result, Ok(output) if output.ran_out_of_input());
+        let result = match result {
+            Ok(output) if output.timed_out => {
+                return Ok(timed_out_response(id, command, timeout, output, structured));
+            }
+            other => other.and_then(|output| output.into_result()),
+        };
+
+        // A failing task is a result for the model to read, not a protocol
+        // fault, so its output goes back as an error result
+        let (mut output, is_error) = match result {
+            Ok(output) => (output, false),
+            Err(e) => (e.to_string(), true),
+        };
+        if ran_out_of_input {
+            output.push_str(if had_input {
+                "\n\nThe task asked for more input than the `stdin` argument provided. Pass an answer for every prompt, one per line."
+            } else {
+                "\n\nThe task tried to read input, but none was given. Pass the answers to its prompts in the `stdin` argument, one per line."
+            });
+        }
+        Ok(tool_result(
+            id,
+            format!("$ angreal {}\n\n{}", command, output),
+            is_error,
+            structured,
+        ))
+    }
+
+    /// Looks up the job named by the `job_id` argument, describing the
+    /// problem if there isn't one.
+    fn find_job(&self, arguments: Option<&Value>) -> std::result::Result<Arc<Job>, InvalidParams> {
+        let job_id = arguments
+            .and_then(|args| args.get("job_id"))
+            .and_then(|id| id.as_str())
+            .ok_or_else(|| {
+                InvalidParams::argument("job_id", "Missing required 'job_id' parameter")
+            })?;
+        self.jobs.get(job_id).ok_or_else(|| {
+            let details = format!(
+                "Unknown job: {} (only the last {} finished jobs are kept)",
+                job_id, MAX_FINISHED_JOBS
+            );
+            InvalidParams::argument("job_id", details)
+        })
+    }
+
+    /// Handles a call to one of the generated `angreal__<path>` tools by
+    /// mapping its arguments back onto the command line.
+    async fn handle_task_tool(
+        &self,
+        id: Option<Value>,
+        params: ToolCallParams,
+    ) -> Result<JsonRpcResponse> {
+        let tree = match self.tree_cache.load().await {
+            Ok(snapshot) => snapshot.tree,
+            Err(e) => return Ok(tool_result(id, e.to_string(), true, None)),
+        };
+
+        let command = tree
+            .commands
+            .iter()
+            .find(|cmd| task_tool_name(&cmd.path()) == params.name);
+        let Some(command) = command else {
+            return Ok(unknown_tool(id, &params.name));
+        };
+
+        let args = task_argv(command, params.arguments.as_ref())?;
+
+        let path = command.path();
+        self.run_angreal_task(
+            id,
+            &path,
+            args,
+            self.run_options(self.config.default_timeout),
+            self.config.max_output_bytes,
+            params.meta.as_ref(),
+        )
+        .await
+    }
+
     async fn handle_tool_call(
         &self,
         id: Option<Value>,
         params: ToolCallParams,
     ) -> Result<JsonRpcResponse> {
         match params.name.as_str() {
-            "angreal_check" => match crate::angreal::check_angreal_project_status().await {
-                Ok(status_info) => Ok(JsonRpcResponse {
-                    jsonrpc: "2.0".to_string(),
-                    id,
-                    result: Some(json!({
-                        "content": [
-                            {
-                                "type": "text",
-                                "text": status_info
-                            }
-                        ]
-                    })),
-                    error: None,
-                }),
-                Err(e) => Ok(JsonRpcResponse {
-                    jsonrpc: "2.0".to_string(),
-                    id,
-                    result: None,
-                    error: Some(JsonRpcError {
-                        code: -32603,
-                        message: "Internal error".to_string(),
-                        data: Some(json!({
-                            "details": e.to_string(),
-                        })),
-                    }),
-                }),
-            },
-            "angreal_run" => {
-                let command = params
-                    .arguments
-                    .as_ref()
-                    .and_then(|args| args.get("command"))
-                    .and_then(|c| c.as_str())
-                    .ok_or_else(|| anyhow::anyhow!("Missing required 'command' parameter"))?;
-
-                let args: Vec<String> = params
-                    .arguments
-                    .as_ref()
-                    .and_then(|args| args.get("args"))
-                    .and_then(|a| a.as_array())
-                    .map(|arr| {
-                        arr.iter()
-                            .filter_map(|v| v.as_str())
-                            .map(String::from)
-                            .collect()
-                    })
-                    .unwrap_or_default();
-
-                match crate::angreal::run_angreal_command(command, &args).await {
-                    Ok(output) => Ok(JsonRpcResponse {
+            "angreal_check" => {
+                match crate::angreal::check_angreal_project_status(&self.tree_cache).await {
+                    Ok(status_info) => Ok(JsonRpcResponse {
                         jsonrpc: "2.0".to_string(),
                         id,
                         result: Some(json!({
                             "content": [
                                 {
                                     "type": "text",
-                                    "text": format!("$ angreal {}\n\n{}", command, output)
+                                    "text": status_info
                                 }
                             ]
                         })),
                         error: None,
                     }),
-                    Err(e) => Ok(JsonRpcResponse {
-                        jsonrpc: "2.0".to_string(),
-                        id,
-                        result: None,
-                        error: Some(JsonRpcError {
-                            code: -32603,
-                            message: "Command execution failed".to_string(),
-                            data: Some(json!({
-                                "details": e.to_string(),
-                            })),
-                        }),
-                    }),
+                    Err(e) => Ok(tool_result(id, e.to_string(), true, None)),
+                }
+            }
+            "angreal_run" => {
+                let (command, args) = command_arguments(params.arguments.as_ref())?;
+
+                let timeout = params
+                    .arguments
+                    .as_ref()
+                    .and_then(|args| args.get("timeout_seconds"))
+                    .map(parse_timeout_seconds)
+                    .transpose()
+                    .map_err(|e| InvalidParams::argument("timeout_seconds", e.to_string()))?
+                    .or(self.config.default_timeout);
+
+                let max_output_bytes = match params
+                    .arguments
+                    .as_ref()
+                    .and_then(|args| args.get("max_output_bytes"))
+                {
+                    Some(value) => value.as_u64().ok_or_else(|| {
+                        InvalidParams::argument(
+                            "max_output_bytes",
+                            "max_output_bytes must be a non-negative integer",
+                        )
+                    })? as usize,
+                    None => self.config.max_output_bytes,
+                };
+
+                let pty = match params.arguments.as_ref().and_then(|args| args.get("pty")) {
+                    Some(value) => value
+                        .as_bool()
+                        .ok_or_else(|| InvalidParams::argument("pty", "pty must be a boolean"))?,
+                    None => false,
+                };
+
+                let env = parse_env(params.arguments.as_ref())?;
+
+                let cwd = match params
+                    .arguments
+                    .as_ref()
+                    .and_then(|args| args.get("cwd"))
+                    .filter(|cwd| !cwd.is_null())
+                {
+                    Some(Value::String(cwd)) => {
+                        let base = self.tree_cache.dir()?;
+                        let dir = resolve_working_dir(&base, Path::new(cwd))
+                            .map_err(|details| InvalidParams::argument("cwd", details))?;
+                        Some(dir)
+                    }
+                    Some(_) => {
+                        return Err(InvalidParams::argument("cwd", "cwd must be a string").into())
+                    }
+                    None => None,
+                };
+
+                let stdin = match params.arguments.as_ref().and_then(|args| args.get("stdin")) {
+                    None | Some(Value::Null) => None,
+                    Some(Value::String(stdin)) => Some(stdin.clone()),
+                    Some(_) => {
+                        return Err(
+                            InvalidParams::argument("stdin", "stdin must be a string").into()
+                        )
+                    }
+                };
+
+                // A cwd inside a nested project runs that project's commands
+                if let Some(rejection) =
+                    check_run_args(&self.tree_cache, cwd.as_deref(), &id, command, &args).await
+                {
+                    return Ok(rejection);
                 }
+
+                self.run_angreal_task(
+                    id,
+                    command,
+                    args,
+                    RunOptions {
+                        pty,
+                        env,
+                        cwd,
+                        stdin,
+                        ..self.run_options(timeout)
+                    },
+                    max_output_bytes,
+                    params.meta.as_ref(),
+                )
+                .await
             }
             "angreal_tree" => {
                 let format = params
@@ -447,48 +1422,813 @@ impl McpServer {
                     .and_then(|f| f.as_str())
                     .unwrap_or("json");
 
-                match crate::angreal::get_angreal_tree(format).await {
-                    Ok(output) => Ok(JsonRpcResponse {
-                        jsonrpc: "2.0".to_string(),
-                        id,
-                        result: Some(json!({
-                            "content": [
+                // The JSON tree is served from the cache, with a note on how
+                // fresh it is; the human rendering always comes from angreal
+                let output = match format {
+                    "json" => self.tree_cache.load().await.map(|snapshot| {
+                        json!([
+                            {
+                                "type": "text",
+                                "text": &*snapshot.json
+                            },
+                            {
+                                "type": "text",
+                                "text": snapshot.freshness()
+                            }
+                        ])
+                    }),
+                    _ => crate::angreal::get_angreal_tree(format)
+                        .await
+                        .map(|output| {
+                            json!([
                                 {
                                     "type": "text",
                                     "text": output
                                 }
-                            ]
+                            ])
+                        }),
+                };
+
+                match output {
+                    Ok(content) => Ok(JsonRpcResponse {
+                        jsonrpc: "2.0".to_string(),
+                        id,
+                        result: Some(json!({
+                            "content": content
                         })),
                         error: None,
                     }),
-                    Err(e) => Ok(JsonRpcResponse {
-                        jsonrpc: "2.0".to_string(),
-                        id,
-                        result: None,
-                        error: Some(JsonRpcError {
-                            code: -32603,
-                            message: "Internal error".to_string(),
-                            data: Some(json!({
-                                "details": e.to_string(),
-                            })),
-                        }),
+                    Err(e) => Ok(tool_result(id, e.to_string(), true, None)),
+                }
+            }
+            "angreal_run_output" => {
+                let arguments = params.arguments.as_ref();
+                let argument = |name: &str| arguments.and_then(|args| args.get(name));
+
+                let Some(run_id) = argument("run_id").and_then(|id| id.as_str()) else {
+                    let details = "Missing required 'run_id' parameter";
+                    return Err(InvalidParams::argument("run_id", details).into());
+                };
+                let stream = match argument("stream").filter(|s| !s.is_null()) {
+                    None => OutputStream::Stdout,
+                    Some(stream) if stream == "stdout" => OutputStream::Stdout,
+                    Some(stream) if stream == "stderr" => OutputStream::Stderr,
+                    Some(other) => {
+                        let details =
+                            format!("Invalid stream {}. Must be 'stdout' or 'stderr'", other);
+                        return Err(InvalidParams::argument("stream", details).into());
+                    }
+                };
+                let offset = count_argument(arguments, "offset")?.unwrap_or(0);
+                let limit = count_argument(arguments, "limit")?
+                    .unwrap_or(DEFAULT_OUTPUT_PAGE_LINES)
+                    .max(1);
+                let grep = match argument("grep") {
+                    None | Some(Value::Null) => None,
+                    Some(Value::String(grep)) => Some(grep.as_str()),
+                    Some(_) => {
+                        return Err(
+                            InvalidParams::argument("grep", "'grep' must be a string").into()
+                        )
+                    }
+                };
+                let Some(run) = self.runs.get(run_id) else {
+                    let details = format!(
+                        "Unknown run: {} (only the last {} runs are kept)",
+                        run_id, MAX_STORED_RUNS
+                    );
+                    return Err(InvalidParams::argument("run_id", details).into());
+                };
+
+                let page = run.page(stream, offset as usize, limit as usize, grep);
+                Ok(json_result(id, serde_json::to_value(page)?))
+            }
+            "angreal_job_start" => {
+                let (command, args) = command_arguments(params.arguments.as_ref())?;
+
+                if let Some(rejection) =
+                    check_run_args(&self.tree_cache, None, &id, command, &args).await
+                {
+                    return Ok(rejection);
+                }
+
+                let timeout = params
+                    .arguments
+                    .as_ref()
+                    .and_then(|args| args.get("timeout_seconds"))
+                    .map(parse_timeout_seconds)
+                    .transpose()
+                    .map_err(|e| InvalidParams::argument("timeout_seconds", e.to_string()))?;
+
+                let job = self
+                    .jobs
+                    .start(command, args, self.run_options(timeout))
+                    .await;
+                Ok(json_result(id, job.status()))
+            }
+            "angreal_job_status" => {
+                match params
+                    .arguments
+                    .as_ref()
+                    .and_then(|args| args.get("job_id"))
+                {
+                    None | Some(Value::Null) => {
+                        let jobs: Vec<Value> =
+                            self.jobs.list().iter().map(|job| job.status()).collect();
+                        Ok(json_result(id, json!({ "jobs": jobs })))
+                    }
+                    Some(_) => {
+                        let job = self.find_job(params.arguments.as_ref())?;
+                        Ok(json_result(id, job.status()))
+                    }
+                }
+            }
+            "angreal_job_output" => {
+                let job = self.find_job(params.arguments.as_ref())?;
+                let from = parse_output_offset(params.arguments.as_ref()).map_err(|e| {
+                    // Blame `offset` unless `byte_offset` was the only one given
+                    let given = |name: &str| {
+                        params
+                            .arguments
+                            .as_ref()
+                            .and_then(|args| args.get(name))
+                            .is_some_and(|v| !v.is_null())
+                    };
+                    let name = if given("byte_offset") && !given("offset") {
+                        "byte_offset"
+                    } else {
+                        "offset"
+                    };
+                    InvalidParams::argument(name, e.to_string())
+                })?;
+                let limit = count_argument(params.arguments.as_ref(), "limit")?
+                    .unwrap_or(DEFAULT_JOB_OUTPUT_LINES)
+                    .max(1);
+
+                let chunk = job.read_output(from, limit as usize);
+                let mut result = serde_json::to_value(chunk)?;
+                result["job_id"] = json!(job.id);
+                result["status"] = job.status()["status"].clone();
+                Ok(json_result(id, result))
+            }
+            "angreal_job_kill" => {
+                let job = self.find_job(params.arguments.as_ref())?;
+                job.kill();
+                Ok(json_result(id, job.status()))
+            }
+            name if name.starts_with(TASK_TOOL_PREFIX) => self.handle_task_tool(id, params).await,
+            _ => Ok(unknown_tool(id, &params.name)),
+        }
+    }
+}
+
+/// How often the project's task files are checked for changes to the tool
+/// list.
+pub const TOOL_WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Lines returned by `angreal_job_output` when no `limit` is given.
+pub const DEFAULT_JOB_OUTPUT_LINES: u64 = 200;
+
+/// Lines returned by `angreal_run_output` when no `limit` is given.
+pub const DEFAULT_OUTPUT_PAGE_LINES: u64 = 200;
+
+/// Prefix of the tools generated for each command in the project's tree.
+pub const TASK_TOOL_PREFIX: &str = "angreal__";
+
+/// Tool name for a command path: `call-testing command-2` becomes
+/// `angreal__call-testing__command-2`. Characters MCP doesn't allow in tool
+/// names are replaced with `_`.
+pub fn task_tool_name(path: &str) -> String {
+    let name = path.split_whitespace().collect::<Vec<_>>().join("__");
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("{}{}", TASK_TOOL_PREFIX, name)
+}
+
+/// The `command` and `args` arguments of `angreal_run` and
+/// `angreal_job_start`.
+fn command_arguments(
+    arguments: Option<&Value>,
+) -> std::result::Result<(&str, Vec<String>), InvalidParams> {
+    let command = arguments
+        .and_then(|args| args.get("command"))
+        .and_then(|c| c.as_str())
+        .ok_or_else(|| {
+            InvalidParams::argument("command", "Missing required 'command' parameter")
+        })?;
+
+    let args: Vec<String> = arguments
+        .and_then(|args| args.get("args"))
+        .and_then(|a| a.as_array())
+        .map(|arr| {
+            arr.iter()
+                .filter_map(|v| v.as_str())
+                .map(String::from)
+                .collect()
+        })
+        .unwrap_or_default();
+
+    Ok((command, args))
+}
+
+/// Reads the optional non-negative integer argument `name`.
+fn count_argument(
+    arguments: Option<&Value>,
+    name: &str,
+) -> std::result::Result<Option<u64>, InvalidParams> {
+    match arguments.and_then(|args| args.get(name)) {
+        None | Some(Value::Null) => Ok(None),
+        Some(value) => value.as_u64().map(Some).ok_or_else(|| {
+            InvalidParams::argument(name, format!("'{}' must be a non-negative integer", name))
+        }),
+    }
+}
+
+/// A tool result carrying `value` as pretty-printed JSON text, for the job
+/// and run output tools.
+fn json_result(id: Option<Value>, value: Value) -> JsonRpcResponse {
+    let text = serde_json::to_string_pretty(&value).unwrap_or_else(|_| value.to_string());
+    JsonRpcResponse {
+        jsonrpc: "2.0".to_string(),
+        id,
+        result: Some(json!({
+            "content": [
+                {
+                    "type": "text",
+                    "text": text
+                }
+            ]
+        })),
+        error: None,
+    }
+}
+
+/// Parses the `env` argument of `angreal_run`, an object of strings.
+fn parse_env(
+    arguments: Option<&Value>,
+) -> std::result::Result<Vec<(String, String)>, InvalidParams> {
+    let env = match arguments.and_then(|args| args.get("env")) {
+        None | Some(Value::Null) => return Ok(Vec::new()),
+        Some(Value::Object(env)) => env,
+        Some(_) => {
+            return Err(InvalidParams::argument(
+                "env",
+                "env must be an object of strings",
+            ))
+        }
+    };
+
+    env.iter()
+        .map(|(name, value)| {
+            let invalid = |details: String| {
+                InvalidParams::new(format!("/arguments/env/{}", pointer_token(name)), details)
+            };
+            let value = value
+                .as_str()
+                .ok_or_else(|| invalid(format!("env value for '{}' must be a string", name)))?;
+            if name.is_empty() || name.contains(['=', '\0']) {
+                return Err(invalid(format!(
+                    "Invalid environment variable name '{}'",
+                    name
+                )));
+            }
+            if value.contains('\0') {
+                return Err(invalid(format!(
+                    "env value for '{}' contains a NUL byte",
+                    name
+                )));
+            }
+            Ok((name.clone(), value.to_string()))
+        })
+        .collect()
+}
+
+/// Reads the params of `tools/call`, reporting which field is wrong if
+/// they don't fit.
+fn parse_tool_call_params(
+    params: Option<Value>,
+) -> std::result::Result<ToolCallParams, InvalidParams> {
+    let Some(params) = params else {
+        return Err(InvalidParams::new("", "tools/call requires params"));
+    };
+    let Value::Object(fields) = &params else {
+        return Err(InvalidParams::new("", "params must be an object"));
+    };
+    match fields.get("name") {
+        Some(Value::String(_)) => {}
+        Some(_) => return Err(InvalidParams::new("/name", "'name' must be a string")),
+        None => {
+            return Err(InvalidParams::new(
+                "/name",
+                "Missing required 'name' parameter",
+            ))
+        }
+    }
+    if fields
+        .get("arguments")
+        .is_some_and(|args| !(args.is_object() || args.is_null()))
+    {
+        return Err(InvalidParams::new(
+            "/arguments",
+            "'arguments' must be an object",
+        ));
+    }
+    if fields
+        .get("_meta")
+        .is_some_and(|meta| !(meta.is_object() || meta.is_null()))
+    {
+        return Err(InvalidParams::new("/_meta", "'_meta' must be an object"));
+    }
+
+    serde_json::from_value(params).map_err(|e| InvalidParams::new("", e.to_string()))
+}
+
+/// The response to a message that isn't valid JSON-RPC.
+pub fn parse_error() -> JsonRpcResponse {
+    JsonRpcResponse {
+        jsonrpc: "2.0".to_string(),
+        id: None,
+        result: None,
+        error: Some(JsonRpcError {
+            code: -32700,
+            message: "Parse error".to_string(),
+            data: None,
+        }),
+    }
+}
+
+/// The answer to a message that is valid JSON but not a JSON-RPC request.
+pub fn invalid_request(id: Option<Value>, details: &str) -> JsonRpcResponse {
+    JsonRpcResponse {
+        jsonrpc: "2.0".to_string(),
+        id,
+        result: None,
+        error: Some(JsonRpcError {
+            code: -32600,
+            message: "Invalid Request".to_string(),
+            data: Some(json!({
+                "details": details,
+            })),
+        }),
+    }
+}
+
+/// A message that is valid JSON but not a JSON-RPC request, with the id
+/// to answer it under if one could be recovered from it.
+#[derive(Debug)]
+pub struct InvalidRequest {
+    pub id: Option<Value>,
+    pub details: String,
+}
+
+impl InvalidRequest {
+    pub fn response(self) -> JsonRpcResponse {
+        invalid_request(self.id, &self.details)
+    }
+}
+
+/// Reads a JSON-RPC request out of a parsed message. A rejected message
+/// keeps its id whenever it has a usable one, so the client can tell which
+/// of its requests was at fault.
+pub fn parse_request(message: Value) -> std::result::Result<JsonRpcRequest, InvalidRequest> {
+    let id = message
+        .get("id")
+        .filter(|id| id.is_string() || id.is_number())
+        .cloned();
+    let invalid = |id, details: &str| InvalidRequest {
+        id,
+        details: details.to_string(),
+    };
+    let Value::Object(fields) = &message else {
+        return Err(invalid(None, "A request must be a JSON object"));
+    };
+
+    let problem = if fields.get("jsonrpc") != Some(&json!("2.0")) {
+        Some("'jsonrpc' must be \"2.0\"")
+    } else if !fields.get("method").is_some_and(Value::is_string) {
+        Some("'method' must be a string")
+    } else if fields
+        .get("id")
+        .is_some_and(|id| !(id.is_string() || id.is_number() || id.is_null()))
+    {
+        Some("'id' must be a string or a number")
+    } else if fields
+        .get("params")
+        .is_some_and(|params| !(params.is_object() || params.is_array()))
+    {
+        Some("'params' must be an object or an array")
+    } else {
+        None
+    };
+    if let Some(problem) = problem {
+        return Err(invalid(id, problem));
+    }
+
+    serde_json::from_value(message).map_err(|e| invalid(id, &e.to_string()))
+}
+
+/// Whether `message` is a response from the client rather than a request.
+/// The server never sends requests, so these are simply ignored.
+pub fn is_client_response(message: &Value) -> bool {
+    message.get("method").is_none()
+        && (message.get("result").is_some() || message.get("error").is_some())
+}
+
+/// A request parameter that failed validation. Handlers may return it as
+/// an error, and it is answered with `-32602 Invalid params`.
+#[derive(Debug)]
+pub struct InvalidParams {
+    /// JSON pointer to the offending value within the request's `params`,
+    /// or `""` when the params as a whole are at fault.
+    pub pointer: String,
+    pub details: String,
+}
+
+impl InvalidParams {
+    pub fn new(pointer: impl Into<String>, details: impl Into<String>) -> Self {
+        Self {
+            pointer: pointer.into(),
+            details: details.into(),
+        }
+    }
+
+    /// A problem with the tool argument `name`.
+    pub fn argument(name: &str, details: impl Into<String>) -> Self {
+        Self::new(format!("/arguments/{}", pointer_token(name)), details)
+    }
+
+    pub fn response(self, id: Option<Value>) -> JsonRpcResponse {
+        JsonRpcResponse {
+            jsonrpc: "2.0".to_string(),
+            id,
+            result: None,
+            error: Some(JsonRpcError {
+                code: -32602,
+                message: "Invalid params".to_string(),
+                data: Some(json!({
+                    "details": self.details,
+                    "pointer": self.pointer,
+                })),
+            }),
+        }
+    }
+}
+
+impl std::fmt::Display for InvalidParams {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.details)
+    }
+}
+
+impl std::error::Error for InvalidParams {}
+
+/// Escapes `key` for use as one reference token of a JSON pointer.
+fn pointer_token(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+/// The answer to a `tools/call` for a tool this server doesn't have.
+fn unknown_tool(id: Option<Value>, name: &str) -> JsonRpcResponse {
+    JsonRpcResponse {
+        jsonrpc: "2.0".to_string(),
+        id,
+        result: None,
+        error: Some(JsonRpcError {
+            code: -32601,
+            message: "Method not found".to_string(),
+            data: Some(json!({
+                "details": format!("Unknown tool: {}", name),
+            })),
+        }),
+    }
+}
+
+/// Checks an `angreal_run` invocation against the command's declared
+/// arguments before anything is spawned, returning the invalid-params
+/// response if it can't succeed. The command is looked up in the project
+/// containing `cwd`, if given. Commands missing from the tree, like `init`,
+/// and projects whose tree can't be loaded are left to angreal.
+async fn check_run_args(
+    tree_cache: &TreeCache,
+    cwd: Option<&Path>,
+    id: &Option<Value>,
+    command: &str,
+    args: &[String],
+) -> Option<JsonRpcResponse> {
+    let snapshot = match cwd {
+        Some(cwd) => tree_cache.load_in(cwd).await,
+        None => tree_cache.load().await,
+    };
+    let tree = snapshot.ok()?.tree;
+    let argv: Vec<String> = command
+        .split_whitespace()
+        .map(String::from)
+        .chain(args.iter().cloned())
+        .collect();
+    let (cmd, rest) = tree.resolve(&argv)?;
+    let errors = cmd.check_args(rest).err()?;
+
+    let signature = format!("angreal {}", cmd.signature());
+    Some(JsonRpcResponse {
+        jsonrpc: "2.0".to_string(),
+        id: id.clone(),
+        result: None,
+        error: Some(JsonRpcError {
+            code: -32602,
+            message: "Invalid params".to_string(),
+            data: Some(json!({
+                "details": format!("{}\n\nUsage: {}", errors, signature),
+                "pointer": "/arguments/args",
+                "signature": signature,
+                "missing": errors.missing,
+                "unknown": errors.unknown,
+                "unexpected": errors.unexpected,
+            })),
+        }),
+    })
+}
+
+/// Builds one MCP tool per command in the project's tree. Flags become
+/// booleans, everything else a string, and `required` arguments are
+/// required properties.
+pub fn task_tools(tree: &CommandTree) -> Vec<Tool> {
+    tree.commands
+        .iter()
+        .map(|cmd| {
+            let path = cmd.path();
+
+            let mut properties = serde_json::Map::new();
+            let mut required = Vec::new();
+            for arg in cmd.arguments.iter().filter(|arg| !arg.name.is_empty()) {
+                let schema_type = match arg.kind() {
+                    ArgumentKind::Flag => "boolean",
+                    ArgumentKind::Option | ArgumentKind::Positional => "string",
+                };
+                properties.insert(
+                    arg.name.clone(),
+                    json!({
+                        "type": schema_type,
+                        "description": arg.help
                     }),
+                );
+                if arg.required {
+                    required.push(arg.name.clone());
                 }
             }
-            _ => Ok(JsonRpcResponse {
-                jsonrpc: "2.0".to_string(),
-                id,
-                result: None,
-                error: Some(JsonRpcError {
-                    code: -32602,
-                    message: "Invalid params".to_string(),
-                    data: Some(json!({
-                        "details": format!("Unknown tool: {}", params.name),
-                    })),
+
+            Tool {
+                name: task_tool_name(&path),
+                title: Some(format!("angreal {}", path)),
+                description: format!(
+                    "{}\n\nRuns `angreal {}` in the project.",
+                    cmd.description, path
+                ),
+                input_schema: json!({
+                    "type": "object",
+                    "properties": properties,
+                    "required": required,
+                    "additionalProperties": false
                 }),
-            }),
+                output_schema: Some(run_output_schema()),
+                annotations: Some(run_annotations()),
+            }
+        })
+        .collect()
+}
+
+/// Turns the arguments of a generated tool call into angreal's argv for
+/// `cmd`. Options come first, positionals follow in declaration order.
+/// Fails on the first argument that is missing, unknown or of the wrong
+/// type.
+pub fn task_argv(
+    cmd: &Command,
+    arguments: Option<&Value>,
+) -> std::result::Result<Vec<String>, InvalidParams> {
+    let empty = serde_json::Map::new();
+    let supplied = match arguments {
+        None | Some(Value::Null) => &empty,
+        Some(Value::Object(map)) => map,
+        Some(_) => {
+            return Err(InvalidParams::new(
+                "/arguments",
+                "Tool arguments must be an object",
+            ))
         }
+    };
+
+    let unknown: Vec<&str> = supplied
+        .keys()
+        .filter(|key| !cmd.arguments.iter().any(|arg| arg.name == **key))
+        .map(|key| key.as_str())
+        .collect();
+    if let Some(first) = unknown.first() {
+        return Err(InvalidParams::argument(
+            first,
+            format!("Unknown arguments: {}", unknown.join(", ")),
+        ));
     }
+
+    let mut argv = Vec::new();
+    let mut positionals = Vec::new();
+    let mut missing = Vec::new();
+    for arg in cmd.arguments.iter().filter(|arg| !arg.name.is_empty()) {
+        let name = arg.name.as_str();
+        let value = supplied.get(name).filter(|v| !v.is_null());
+
+        match (arg.kind(), value) {
+            (ArgumentKind::Flag, Some(Value::Bool(true))) => argv.push(arg.cli_flag()),
+            (ArgumentKind::Flag, Some(Value::Bool(false))) if arg.required => missing.push(name),
+            (ArgumentKind::Flag, Some(Value::Bool(false))) => {}
+            (ArgumentKind::Flag, Some(_)) => {
+                return Err(InvalidParams::argument(
+                    name,
+                    format!("Argument '{}' must be a boolean", name),
+                ));
+            }
+            (kind, Some(value)) => {
+                let value = match value {
+                    Value::String(s) => s.clone(),
+                    Value::Number(_) | Value::Bool(_) => value.to_string(),
+                    _ => {
+                        return Err(InvalidParams::argument(
+                            name,
+                            format!("Argument '{}' must be a string", name),
+                        ))
+                    }
+                };
+                if kind == ArgumentKind::Option {
+                    argv.push(arg.cli_flag());
+                    argv.push(value);
+                } else {
+                    positionals.push(value);
+                }
+            }
+            (_, None) if arg.required => missing.push(name),
+            (_, None) => {}
+        }
+    }
+
+    if let Some(first) = missing.first() {
+        return Err(InvalidParams::argument(
+            first,
+            format!("Missing required arguments: {}", missing.join(", ")),
+        ));
+    }
+
+    argv.extend(positionals);
+    Ok(argv)
+}
+
+/// Parses a `timeout_seconds` argument into a positive duration.
+pub fn parse_timeout_seconds(value: &Value) -> Result<Duration> {
+    let seconds = value
+        .as_f64()
+        .ok_or_else(|| anyhow::anyhow!("'timeout_seconds' must be a number"))?;
+    if !seconds.is_finite() || seconds <= 0.0 {
+        anyhow::bail!("'timeout_seconds' must be a positive number of seconds");
+    }
+    Duration::try_from_secs_f64(seconds)
+        .map_err(|_| anyhow::anyhow!("'timeout_seconds' is too large"))
+}
+
+/// Hints for the tools that run a task (`angreal_run`, `angreal_job_start`
+/// and the per-task tools): tasks can do anything, including touching the
+/// network, and running one twice isn't guaranteed to be harmless.
+fn run_annotations() -> Value {
+    json!({
+        "readOnlyHint": false,
+        "destructiveHint": true,
+        "idempotentHint": false,
+        "openWorldHint": true
+    })
+}
+
+/// Schema of the `structuredContent` returned by `angreal_run` and the
+/// per-task tools.
+pub fn run_output_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "exit_code": {
+                "type": ["integer", "null"],
+                "description": "Exit code of the task; null if it was killed by a signal or timed out"
+            },
+            "signal": {
+                "type": ["integer", "null"],
+                "description": "Signal that ended the task, if any"
+            },
+            "duration_ms": {
+                "type": "integer",
+                "description": "Wall-clock run time in milliseconds"
+            },
+            "stdout": {"type": "string"},
+            "stderr": {"type": "string"},
+            "command_argv": {
+                "type": "array",
+                "items": {"type": "string"},
+                "description": "The exact command line that was run"
+            },
+            "timed_out": {"type": "boolean"},
+            "run_id": {
+                "type": "string",
+                "description": "Id to page through the full output with angreal_run_output"
+            },
+            "stdout_truncated": {
+                "type": "boolean",
+                "description": "Whether stdout was shortened before being returned"
+            },
+            "stderr_truncated": {
+                "type": "boolean",
+                "description": "Whether stderr was shortened before being returned"
+            }
+        },
+        "required": [
+            "exit_code",
+            "signal",
+            "duration_ms",
+            "stdout",
+            "stderr",
+            "command_argv",
+            "timed_out",
+            "run_id",
+            "stdout_truncated",
+            "stderr_truncated"
+        ]
+    })
+}
+
+/// The parts of a run's `structuredContent` that come straight from its
+/// output; see [`run_output_schema`].
+fn structured_run_output(output: &crate::angreal::CommandOutput) -> Value {
+    json!({
+        "exit_code": output.exit_code(),
+        "signal": output.signal(),
+        "duration_ms": output.duration.as_millis() as u64,
+        "stdout": output.stdout,
+        "stderr": output.stderr,
+        "command_argv": output.argv,
+        "timed_out": output.timed_out,
+    })
+}
+
+/// A `tools/call` result carrying `text`, flagged with `isError` when the
+/// tool itself failed.
+fn tool_result(
+    id: Option<Value>,
+    text: String,
+    is_error: bool,
+    structured: Option<Value>,
+) -> JsonRpcResponse {
+    let mut result = json!({
+        "content": [
+            {
+                "type": "text",
+                "text": text
+            }
+        ]
+    });
+    if is_error {
+        result["isError"] = json!(true);
+    }
+    if let Some(structured) = structured {
+        result["structuredContent"] = structured;
+    }
+
+    JsonRpcResponse {
+        jsonrpc: "2.0".to_string(),
+        id,
+        result: Some(result),
+        error: None,
+    }
+}
+
+fn timed_out_response(
+    id: Option<Value>,
+    command: &str,
+    timeout: Option<Duration>,
+    output: crate::angreal::CommandOutput,
+    structured: Option<Value>,
+) -> JsonRpcResponse {
+    let limit = timeout
+        .map(|t| format!("{:?}", t))
+        .unwrap_or_else(|| "the time limit".to_string());
+    let mut text = format!(
+        "$ angreal {}\n\nTimed out after {}; the task and its process group were killed.\n\nPartial output:\n{}",
+        command, limit, output.stdout
+    );
+    if !output.stderr.trim().is_empty() {
+        text.push_str(&format!("\n\nStderr:\n{}", output.stderr));
+    }
+
+    tool_result(id, text, true, structured)
 }
 
 impl Default for McpServer {
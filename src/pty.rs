@@ -0,0 +1,130 @@
+//! Running tasks attached to a pseudo-terminal instead of pipes.
+
+use std::io;
+use std::os::fd::{FromRawFd, OwnedFd};
+use std::process::Stdio;
+
+/// The terminal's default end-of-file character, Ctrl-D.
+const EOF: u8 = 0x04;
+
+/// Window size reported to tasks; wide enough that few lines get wrapped.
+const COLUMNS: u16 = 200;
+const ROWS: u16 = 50;
+
+/// Attaches `cmd` to a new pseudo-terminal, which becomes its stdin,
+/// stdout, stderr and controlling terminal, and returns the master side to
+/// read the combined output from.
+///
+/// The task is started in a new session, which also makes it the leader of
+/// its own process group, so this replaces `process_group(0)`.
+pub(crate) fn attach(cmd: &mut tokio::process::Command) -> io::Result<tokio::fs::File> {
+    let (master, slave) = open()?;
+
+    cmd.stdin(Stdio::from(slave.try_clone()?))
+        .stdout(Stdio::from(slave.try_clone()?))
+        .stderr(Stdio::from(slave));
+
+    // SAFETY: setsid and ioctl are async-signal-safe, and the closure
+    // touches nothing but the child's own file descriptors.
+    unsafe {
+        cmd.pre_exec(|| {
+            if libc::setsid() == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            // The slave is stdin by now; make it the controlling terminal
+            if libc::ioctl(0, libc::TIOCSCTTY as _, 0) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+
+    Ok(tokio::fs::File::from_std(std::fs::File::from(master)))
+}
+
+/// Ends `input` so that a task reading it from the terminal sees end of
+/// file after it. An unfinished last line is completed first, since a
+/// terminal only hands over whole lines and line editors such as readline
+/// ignore end-of-file in the middle of one.
+pub(crate) fn terminate_input(input: &mut Vec<u8>) {
+    if !input.is_empty() && !input.ends_with(b"\n") {
+        input.push(b'\n');
+    }
+    input.push(EOF);
+}
+
+/// Whether `error` is the master side reporting that every process holding
+/// the terminal has closed it, which is how a pty signals end of output.
+pub(crate) fn is_hangup(error: &io::Error) -> bool {
+    error.raw_os_error() == Some(libc::EIO)
+}
+
+fn open() -> io::Result<(OwnedFd, OwnedFd)> {
+    let mut master = -1;
+    let mut slave = -1;
+    let size = libc::winsize {
+        ws_row: ROWS,
+        ws_col: COLUMNS,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+
+    // SAFETY: the out-pointers are valid for writes, a null name is allowed
+    // and the termios and winsize arguments are only read.
+    let result = unsafe {
+        libc::openpty(
+            &mut master,
+            &mut slave,
+            std::ptr::null_mut(),
+            std::ptr::null(),
+            &size,
+        )
+    };
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // SAFETY: openpty succeeded, so both are open descriptors owned by us.
+    let (master, slave) = unsafe { (OwnedFd::from_raw_fd(master), OwnedFd::from_raw_fd(slave)) };
+    // Input is written up front, so echoing it back would just interleave
+    // it with the task's output
+    disable_echo(&slave)?;
+    // Neither end should leak into the task (or any other child) beyond
+    // the stdio copies made for it.
+    for fd in [&master, &slave] {
+        set_cloexec(fd)?;
+    }
+    Ok((master, slave))
+}
+
+fn disable_echo(fd: &OwnedFd) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: termios is plain data that tcgetattr fills in completely
+    // before it is read.
+    unsafe {
+        let mut termios: libc::termios = std::mem::zeroed();
+        if libc::tcgetattr(fd.as_raw_fd(), &mut termios) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        termios.c_lflag &= !libc::ECHO;
+        if libc::tcsetattr(fd.as_raw_fd(), libc::TCSANOW, &termios) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+fn set_cloexec(fd: &OwnedFd) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: fcntl on a descriptor we own, with no pointer arguments.
+    unsafe {
+        let flags = libc::fcntl(fd.as_raw_fd(), libc::F_GETFD);
+        if flags == -1 || libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, flags | libc::FD_CLOEXEC) == -1
+        {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
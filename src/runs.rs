@@ -0,0 +1,238 @@
+use crate::angreal::OutputStream;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// How many finished runs keep their full output; older ones are dropped.
+pub const MAX_STORED_RUNS: usize = 20;
+
+/// Most output kept for one run, across both streams. Beyond it only the
+/// head and tail are stored, like [`truncate_output`] does for results.
+pub const MAX_STORED_RUN_BYTES: usize = 4 * 1024 * 1024;
+
+/// Output limit applied to `angreal_run` results unless configured otherwise.
+pub const DEFAULT_MAX_OUTPUT_BYTES: usize = 32 * 1024;
+
+/// Full output of recent `angreal_run` calls, so results can be truncated
+/// without losing anything the model may want to look at later.
+#[derive(Debug, Default)]
+pub struct RunStore {
+    runs: Mutex<VecDeque<Arc<StoredRun>>>,
+    next_id: AtomicU64,
+}
+
+#[derive(Debug)]
+pub struct StoredRun {
+    pub id: String,
+    pub argv: Vec<String>,
+    pub stdout: String,
+    pub stderr: String,
+    /// Bytes cut from the middle of each stream to fit
+    /// [`MAX_STORED_RUN_BYTES`].
+    pub stdout_dropped: usize,
+    pub stderr_dropped: usize,
+}
+
+/// A page of a stored run's output as returned by `angreal_run_output`.
+#[derive(Debug, Clone, Serialize)]
+pub struct OutputPage {
+    pub run_id: String,
+    pub stream: OutputStream,
+    /// Lines in the stream, or lines matching `grep` when filtering.
+    pub total_lines: usize,
+    pub offset: usize,
+    pub next_offset: usize,
+    pub more: bool,
+    /// The selected lines, each prefixed with its 1-based line number.
+    pub output: String,
+}
+
+impl StoredRun {
+    pub fn stream(&self, stream: OutputStream) -> &str {
+        match stream {
+            OutputStream::Stdout => &self.stdout,
+            OutputStream::Stderr => &self.stderr,
+        }
+    }
+
+    /// Bytes of `stream` that weren't kept.
+    pub fn dropped(&self, stream: OutputStream) -> usize {
+        match stream {
+            OutputStream::Stdout => self.stdout_dropped,
+            OutputStream::Stderr => self.stderr_dropped,
+        }
+    }
+
+    /// Returns up to `limit` lines of `stream` starting at `offset`. With
+    /// `grep`, only lines containing it are considered and `offset` counts
+    /// matches.
+    pub fn page(
+        &self,
+        stream: OutputStream,
+        offset: usize,
+        limit: usize,
+        grep: Option<&str>,
+    ) -> OutputPage {
+        let lines: Vec<(usize, &str)> = self
+            .stream(stream)
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| grep.is_none_or(|pattern| line.contains(pattern)))
+            .collect();
+
+        let selected = lines.iter().skip(offset).take(limit);
+        let output: String = selected
+            .clone()
+            .map(|(number, line)| format!("{:>6}: {}\n", number + 1, line))
+            .collect();
+        let next_offset = (offset + selected.count()).max(offset.min(lines.len()));
+
+        OutputPage {
+            run_id: self.id.clone(),
+            stream,
+            total_lines: lines.len(),
+            offset,
+            next_offset,
+            more: next_offset < lines.len(),
+            output,
+        }
+    }
+}
+
+impl RunStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stores a run's output and returns it under its new id. Output over
+    /// [`MAX_STORED_RUN_BYTES`] loses its middle; a stream that needs less
+    /// than half of the limit leaves the rest to the other.
+    pub fn store(&self, argv: Vec<String>, stdout: String, stderr: String) -> Arc<StoredRun> {
+        let id = format!("run-{}", self.next_id.fetch_add(1, Ordering::Relaxed) + 1);
+        let stderr_budget = stderr
+            .len()
+            .min(MAX_STORED_RUN_BYTES / 2)
+            .max(MAX_STORED_RUN_BYTES.saturating_sub(stdout.len()));
+        let (stdout, stdout_dropped) = keep_ends(stdout, MAX_STORED_RUN_BYTES - stderr_budget);
+        let (stderr, stderr_dropped) = keep_ends(stderr, stderr_budget);
+        let run = Arc::new(StoredRun {
+            id,
+            argv,
+            stdout,
+            stderr,
+            stdout_dropped,
+            stderr_dropped,
+        });
+
+        let mut runs = self.runs.lock().unwrap();
+        runs.push_back(Arc::clone(&run));
+        while runs.len() > MAX_STORED_RUNS {
+            runs.pop_front();
+        }
+        run
+    }
+
+    pub fn get(&self, id: &str) -> Option<Arc<StoredRun>> {
+        self.runs
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|run| run.id == id)
+            .cloned()
+    }
+}
+
+/// Shortens `stream` of `run` to about `max_bytes` by keeping its head and
+/// tail and replacing the middle with a marker pointing at the run. Cuts
+/// fall on line boundaries where possible, and a limit of 0 means no limit.
+/// Returns the text and whether anything was removed.
+pub fn truncate_output(run: &StoredRun, stream: OutputStream, max_bytes: usize) -> (String, bool) {
+    let text = run.stream(stream);
+    if max_bytes == 0 || text.len() <= max_bytes {
+        return (text.to_string(), false);
+    }
+
+    let (head_end, tail_start) = split_ends(text, max_bytes);
+    let omitted = &text[head_end..tail_start];
+    let stored = match run.dropped(stream) {
+        0 => "the full output".to_string(),
+        dropped => format!("the stored output, itself missing {} bytes,", dropped),
+    };
+    let marker = format!(
+        "[... {} bytes ({} lines) omitted; page through {} with angreal_run_output using run_id \"{}\" ...]\n",
+        omitted.len(),
+        omitted.lines().count(),
+        stored,
+        run.id
+    );
+    (join_ends(text, head_end, tail_start, &marker), true)
+}
+
+/// Cuts the middle out of `text` if it is longer than `max_bytes`,
+/// returning what is left and how many bytes went.
+fn keep_ends(text: String, max_bytes: usize) -> (String, usize) {
+    if text.len() <= max_bytes {
+        return (text, 0);
+    }
+
+    let (head_end, tail_start) = split_ends(&text, max_bytes);
+    let dropped = &text[head_end..tail_start];
+    let marker = format!(
+        "[... {} bytes ({} lines) not kept; runs store at most {} bytes of output ...]\n",
+        dropped.len(),
+        dropped.lines().count(),
+        MAX_STORED_RUN_BYTES
+    );
+    let dropped = dropped.len();
+    (join_ends(&text, head_end, tail_start, &marker), dropped)
+}
+
+/// Where the head of `text` ends and its tail starts when keeping about
+/// `max_bytes` of it.
+fn split_ends(text: &str, max_bytes: usize) -> (usize, usize) {
+    let head_end = cut_back(text, max_bytes / 2);
+    let tail_start = cut_forward(text, text.len() - max_bytes / 2).max(head_end);
+    (head_end, tail_start)
+}
+
+/// `text` with everything between `head_end` and `tail_start` replaced by
+/// `marker`, which goes on a line of its own.
+fn join_ends(text: &str, head_end: usize, tail_start: usize, marker: &str) -> String {
+    let head = &text[..head_end];
+    let separator = if head.is_empty() || head.ends_with('\n') {
+        ""
+    } else {
+        "\n"
+    };
+    format!("{}{}{}{}", head, separator, marker, &text[tail_start..])
+}
+
+/// The end of the last whole line before `limit`, or `limit` itself (on a
+/// char boundary) if there is no line break.
+fn cut_back(text: &str, limit: usize) -> usize {
+    match text[..floor_char_boundary(text, limit)].rfind('\n') {
+        Some(newline) => newline + 1,
+        None => floor_char_boundary(text, limit),
+    }
+}
+
+/// The start of the first whole line after `start`, or `start` itself (on
+/// a char boundary) if there is no line break.
+fn cut_forward(text: &str, start: usize) -> usize {
+    let start = floor_char_boundary(text, start);
+    if text[..start].ends_with('\n') {
+        return start;
+    }
+    match text[start..].find('\n') {
+        Some(newline) if start + newline + 1 < text.len() => start + newline + 1,
+        _ => start,
+    }
+}
+
+fn floor_char_boundary(text: &str, mut index: usize) -> usize {
+    while !text.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
@@ -0,0 +1,67 @@
+//! Serving MCP on a Unix domain socket. Every connection speaks the same
+//! newline-delimited JSON as stdio and is a session of the one server.
+
+use crate::mcp::McpServer;
+use crate::transport::{self, LineTransport};
+use std::future::Future;
+use std::io;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::Path;
+use tokio::io::BufReader;
+use tokio::net::{UnixListener, UnixStream};
+
+/// Binds a listening socket at `path`, readable and writable by the
+/// current user only.
+///
+/// A socket file left behind by a server that is no longer running is
+/// replaced; one that still accepts connections, or any other kind of
+/// file, is an error.
+pub async fn bind(path: &Path) -> io::Result<UnixListener> {
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ));
+        }
+        if UnixStream::connect(path).await.is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("Another server is already listening on {}", path.display()),
+            ));
+        }
+        std::fs::remove_file(path)?;
+    }
+
+    let listener = UnixListener::bind(path)?;
+    // The server runs project tasks for whoever connects
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+/// Accepts connections on `listener` until `shutdown` resolves, serving an
+/// MCP session of `base` on each. Sessions still open at shutdown have
+/// their requests cancelled, and background jobs are killed.
+pub async fn serve(
+    base: McpServer,
+    listener: UnixListener,
+    shutdown: impl Future<Output = ()>,
+) -> io::Result<()> {
+    let connections = transport::accept(
+        || async { listener.accept().await.map(|(stream, _)| stream) },
+        |stream, stop| {
+            let (reader, writer) = stream.into_split();
+            let connection = LineTransport::new(BufReader::new(reader), writer);
+            let session = transport::serve(base.session(), connection, stop.requested());
+            async move {
+                let _ = session.await;
+            }
+        },
+        shutdown,
+    )
+    .await;
+
+    connections.close().await;
+    base.kill_jobs();
+    Ok(())
+}
@@ -0,0 +1,126 @@
+//! Turns output written for a terminal into plain text.
+
+const ESC: char = '\u{1b}';
+const BEL: char = '\u{7}';
+const BACKSPACE: char = '\u{8}';
+
+/// What a terminal does with one piece of output.
+enum Piece {
+    Char(char),
+    /// A control sequence: its parameter bytes and final byte.
+    Csi(String, char),
+}
+
+/// Cleans task output for the client: removes ANSI/VT escape sequences
+/// and replays carriage returns, backspaces and line erases, so a
+/// progress bar leaves only the state it was last drawn in.
+pub fn clean_output(text: &str) -> String {
+    let mut clean = String::with_capacity(text.len());
+    let mut line = Line::default();
+    scan(text, |piece| match piece {
+        Piece::Char('\n') => {
+            clean.extend(line.cells.drain(..));
+            clean.push('\n');
+            line.cursor = 0;
+        }
+        Piece::Char('\r') => line.cursor = 0,
+        Piece::Char(BACKSPACE) => line.cursor = line.cursor.saturating_sub(1),
+        Piece::Char(c) => line.write(c),
+        Piece::Csi(params, 'K') => line.erase(&params),
+        Piece::Csi(..) => {}
+    });
+    clean.extend(line.cells);
+    clean
+}
+
+/// Splits `text` into printable characters and control sequences, dropping
+/// every other escape sequence and control character.
+fn scan(text: &str, mut emit: impl FnMut(Piece)) {
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            ESC => match chars.next() {
+                Some('[') => emit(control_sequence(&mut chars)),
+                // OSC, DCS, SOS, PM and APC run until a string terminator
+                Some(']' | 'P' | 'X' | '^' | '_') => skip_string(&mut chars),
+                // Other escapes: intermediate bytes, then a final byte
+                Some(mut next) => {
+                    while ('\u{20}'..='\u{2f}').contains(&next) {
+                        match chars.next() {
+                            Some(following) => next = following,
+                            None => break,
+                        }
+                    }
+                }
+                None => {}
+            },
+            '\u{9b}' => emit(control_sequence(&mut chars)),
+            '\u{90}' | '\u{98}' | '\u{9d}' | '\u{9e}' | '\u{9f}' => skip_string(&mut chars),
+            '\t' | '\n' | '\r' | BACKSPACE => emit(Piece::Char(c)),
+            c if c.is_control() => {}
+            c => emit(Piece::Char(c)),
+        }
+    }
+}
+
+/// Reads a control sequence up to and including its final byte.
+fn control_sequence(chars: &mut impl Iterator<Item = char>) -> Piece {
+    let mut params = String::new();
+    for c in chars {
+        if ('\u{40}'..='\u{7e}').contains(&c) {
+            return Piece::Csi(params, c);
+        }
+        params.push(c);
+    }
+    // Cut off before its final byte; nothing to act on
+    Piece::Csi(params, '\0')
+}
+
+/// Skips to the end of a control string, terminated by BEL or ST.
+fn skip_string(chars: &mut std::iter::Peekable<impl Iterator<Item = char>>) {
+    while let Some(c) = chars.next() {
+        match c {
+            BEL | '\u{9c}' => break,
+            ESC => {
+                if chars.peek() == Some(&'\\') {
+                    chars.next();
+                }
+                break;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The line currently being drawn, with the cursor's column in it.
+#[derive(Default)]
+struct Line {
+    cells: Vec<char>,
+    cursor: usize,
+}
+
+impl Line {
+    fn write(&mut self, c: char) {
+        if self.cursor < self.cells.len() {
+            self.cells[self.cursor] = c;
+        } else {
+            self.cells.resize(self.cursor, ' ');
+            self.cells.push(c);
+        }
+        self.cursor += 1;
+    }
+
+    /// Erase in line (`CSI K`): to the end, to the start, or all of it.
+    fn erase(&mut self, params: &str) {
+        match params {
+            "" | "0" => self.cells.truncate(self.cursor),
+            "1" => {
+                let end = (self.cursor + 1).min(self.cells.len());
+                self.cells[..end].fill(' ');
+            }
+            "2" => self.cells.clear(),
+            _ => {}
+        }
+    }
+}
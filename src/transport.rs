@@ -0,0 +1,346 @@
+//! How MCP messages get to and from a client, kept apart from what the
+//! server does with them.
+
+use crate::mcp::{
+    invalid_request, is_client_response, parse_error, parse_request, JsonRpcMessage, McpServer,
+    TOOL_WATCH_INTERVAL,
+};
+use anyhow::Result;
+use serde_json::Value;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinSet;
+
+/// A connection to one client, carrying serialized JSON-RPC messages.
+pub trait Transport: Send {
+    /// Waits for the next message. Returns `None` once the client has gone
+    /// away. Must be cancel-safe: if the future is dropped before it
+    /// completes, no part of a message may be lost.
+    fn receive(&mut self) -> impl Future<Output = Result<Option<String>>> + Send;
+
+    /// Delivers one message to the client.
+    fn send(&mut self, message: String) -> impl Future<Output = Result<()>> + Send;
+
+    /// Flushes anything still buffered and tells the client no more
+    /// messages will follow.
+    fn close(&mut self) -> impl Future<Output = Result<()>> + Send;
+}
+
+/// Newline-delimited JSON over a byte stream, as used on stdio.
+pub struct LineTransport<R, W> {
+    reader: R,
+    writer: W,
+    /// A message read up to, but not including, its newline.
+    partial: Vec<u8>,
+}
+
+impl<R, W> LineTransport<R, W>
+where
+    R: AsyncBufRead + Unpin + Send,
+    W: AsyncWrite + Unpin + Send,
+{
+    pub fn new(reader: R, writer: W) -> Self {
+        Self {
+            reader,
+            writer,
+            partial: Vec::new(),
+        }
+    }
+}
+
+/// The server's own stdin and stdout.
+pub fn stdio() -> LineTransport<BufReader<tokio::io::Stdin>, tokio::io::Stdout> {
+    LineTransport::new(BufReader::new(tokio::io::stdin()), tokio::io::stdout())
+}
+
+impl<R, W> Transport for LineTransport<R, W>
+where
+    R: AsyncBufRead + Unpin + Send,
+    W: AsyncWrite + Unpin + Send,
+{
+    async fn receive(&mut self) -> Result<Option<String>> {
+        loop {
+            // `read_until` appends to `partial` as it goes, so a cancelled
+            // read leaves what it got there for the next call
+            let read = self.reader.read_until(b'\n', &mut self.partial).await?;
+            if read == 0 && self.partial.is_empty() {
+                return Ok(None);
+            }
+            if read == 0 || self.partial.ends_with(b"\n") {
+                let line = std::mem::take(&mut self.partial);
+                let line = String::from_utf8_lossy(&line);
+                if line.trim().is_empty() {
+                    continue;
+                }
+                return Ok(Some(line.trim().to_string()));
+            }
+        }
+    }
+
+    async fn send(&mut self, message: String) -> Result<()> {
+        self.writer.write_all(message.as_bytes()).await?;
+        self.writer.write_all(b"\n").await?;
+        self.writer.flush().await?;
+        Ok(())
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        self.writer.shutdown().await?;
+        Ok(())
+    }
+}
+
+/// One end of an in-memory connection; see [`duplex`].
+pub struct MemoryTransport {
+    incoming: mpsc::UnboundedReceiver<String>,
+    outgoing: Option<mpsc::UnboundedSender<String>>,
+}
+
+/// A connected pair of in-memory transports, for embedding the server in
+/// another program or driving it from tests. Whatever one end sends, the
+/// other receives.
+pub fn duplex() -> (MemoryTransport, MemoryTransport) {
+    let (a_tx, a_rx) = mpsc::unbounded_channel();
+    let (b_tx, b_rx) = mpsc::unbounded_channel();
+    (
+        MemoryTransport {
+            incoming: a_rx,
+            outgoing: Some(b_tx),
+        },
+        MemoryTransport {
+            incoming: b_rx,
+            outgoing: Some(a_tx),
+        },
+    )
+}
+
+impl Transport for MemoryTransport {
+    async fn receive(&mut self) -> Result<Option<String>> {
+        Ok(self.incoming.recv().await)
+    }
+
+    async fn send(&mut self, message: String) -> Result<()> {
+        self.outgoing
+            .as_ref()
+            .and_then(|outgoing| outgoing.send(message).ok())
+            .ok_or_else(|| anyhow::anyhow!("The other end of the transport is closed"))
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        self.outgoing = None;
+        Ok(())
+    }
+}
+
+/// Why [`serve`] returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ending {
+    /// The client closed the connection. Requests still running were
+    /// allowed to finish and their responses were sent.
+    Closed,
+    /// `shutdown` resolved; requests still running were cancelled.
+    Terminated,
+}
+
+/// Runs one MCP session for `server` over `transport` until the client
+/// disconnects or `shutdown` resolves.
+///
+/// Background jobs outlive the session, since other sessions may share
+/// them; killing them is up to the caller.
+pub async fn serve<T: Transport>(
+    server: McpServer,
+    mut transport: T,
+    shutdown: impl Future<Output = ()>,
+) -> Result<Ending> {
+    // Responses and notifications from concurrently running requests all
+    // go through one channel, so they are written whole and one at a time
+    let (tx, mut rx) = mpsc::unbounded_channel::<JsonRpcMessage>();
+    let server = Arc::new(server.with_notifier(tx.clone()));
+    let watcher = server.watch_tools(TOOL_WATCH_INTERVAL);
+    let mut tasks = JoinSet::new();
+    tokio::pin!(shutdown);
+
+    let ending = loop {
+        // Reap finished handlers so the set doesn't grow unbounded
+        while tasks.try_join_next().is_some() {}
+
+        let message = tokio::select! {
+            message = transport.receive() => message,
+            Some(outgoing) = rx.recv() => {
+                transport.send(serde_json::to_string(&outgoing)?).await?;
+                continue;
+            }
+            () = &mut shutdown => {
+                // Abort running tasks rather than leaving their process
+                // groups behind
+                server.cancel_all();
+                break Ending::Terminated;
+            }
+        };
+        // A read error means the client is gone as surely as EOF does
+        let Ok(Some(message)) = message else {
+            break Ending::Closed;
+        };
+
+        let request = match serde_json::from_str::<Value>(&message) {
+            Ok(Value::Array(batch)) if batch.is_empty() => {
+                let _ = tx.send(invalid_request(None, "A batch must not be empty").into());
+                continue;
+            }
+            Ok(Value::Array(batch)) => {
+                // Like `initialize`, a batch's notifications and handshake are
+                // dealt with before the next message is read
+                let handler = server.respond_batch(batch).await;
+                let tx = tx.clone();
+                tasks.spawn(async move {
+                    let responses = handler.await;
+                    // A batch of nothing but notifications goes unanswered
+                    if !responses.is_empty() {
+                        let _ = tx.send(responses.into());
+                    }
+                });
+                continue;
+            }
+            // The server never sends requests, so there's nothing a
+            // response from the client could answer
+            Ok(message) if is_client_response(&message) => continue,
+            Ok(message) => parse_request(message).map_err(|rejection| rejection.response()),
+            Err(_) => Err(parse_error()),
+        };
+
+        match request {
+            Ok(request) if request.is_notification() => {
+                // Notifications are handled inline, in arrival order, and
+                // never answered
+                server.handle_notification(request);
+            }
+            Ok(request) => {
+                let is_initialize = request.method == "initialize";
+                let handler = server.respond(request);
+                let tx = tx.clone();
+
+                let respond = async move {
+                    // Cancelled requests must not be answered
+                    if let Some(response) = handler.await {
+                        let _ = tx.send(response.into());
+                    }
+                };
+
+                if is_initialize {
+                    // The handshake completes before anything else is read,
+                    // so pipelined requests see an initialized session
+                    respond.await;
+                } else {
+                    // Long-running tasks mustn't hold up further messages
+                    tasks.spawn(respond);
+                }
+            }
+            Err(rejection) => {
+                let _ = tx.send(rejection.into());
+            }
+        }
+    };
+
+    // Refuse new work, then let in-flight requests finish and deliver
+    // their responses
+    server.begin_shutdown();
+    watcher.abort();
+    drop(tx);
+    loop {
+        tokio::select! {
+            Some(outgoing) = rx.recv() => {
+                transport.send(serde_json::to_string(&outgoing)?).await?;
+            }
+            finished = tasks.join_next() => {
+                if finished.is_none() {
+                    break;
+                }
+            }
+        }
+    }
+    // With the handlers done, only the server's own notifier can still be
+    // holding the channel open
+    drop(server);
+    while let Some(outgoing) = rx.recv().await {
+        transport.send(serde_json::to_string(&outgoing)?).await?;
+    }
+    transport.close().await?;
+
+    Ok(ending)
+}
+
+/// How long connections get to wind down after shutdown before they are
+/// dropped.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Tells a connection served by [`accept`] that the server is shutting
+/// down.
+pub struct Stop(watch::Receiver<bool>);
+
+impl Stop {
+    /// Resolves once shutdown has begun.
+    pub async fn requested(mut self) {
+        let _ = self.0.wait_for(|&stop| stop).await;
+    }
+}
+
+/// The connections a listener accepted, each served on a task of its own.
+pub struct Connections {
+    tasks: JoinSet<()>,
+    stop: watch::Sender<bool>,
+}
+
+impl Connections {
+    /// Signals every connection to stop and waits for them to finish,
+    /// dropping those still open after a few seconds.
+    pub async fn close(mut self) {
+        let _ = self.stop.send(true);
+        let _ = tokio::time::timeout(CLOSE_TIMEOUT, async {
+            while self.tasks.join_next().await.is_some() {}
+        })
+        .await;
+        self.tasks.abort_all();
+    }
+}
+
+/// Accepts connections through `accept` until `shutdown` resolves, handing
+/// each to `serve` along with a [`Stop`] signal. Returns the connections
+/// still open, for the caller to [`close`](Connections::close) once it has
+/// done its own cleanup.
+pub async fn accept<S, A, C>(
+    mut accept: impl FnMut() -> A,
+    mut serve: impl FnMut(S, Stop) -> C,
+    shutdown: impl Future<Output = ()>,
+) -> Connections
+where
+    A: Future<Output = std::io::Result<S>>,
+    C: Future<Output = ()> + Send + 'static,
+{
+    tokio::pin!(shutdown);
+    let (stop, stopped) = watch::channel(false);
+    let mut tasks = JoinSet::new();
+
+    loop {
+        let connection = tokio::select! {
+            accepted = accept() => match accepted {
+                Ok(connection) => connection,
+                Err(_) => {
+                    // Usually out of file descriptors; give connections a
+                    // moment to close instead of spinning
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            },
+            () = &mut shutdown => break,
+        };
+        tasks.spawn(serve(connection, Stop(stopped.clone())));
+
+        // Reap finished connections so the set doesn't grow unbounded
+        while tasks.try_join_next().is_some() {}
+    }
+
+    Connections { tasks, stop }
+}
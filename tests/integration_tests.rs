@@ -24,8 +24,8 @@ async fn test_mcp_initialize() {
     assert!(response["result"]["protocolVersion"].is_string());
     assert!(response["result"]["capabilities"]["tools"].is_object());
     assert_eq!(
-        response["result"]["capabilities"]["tools"]["list_changed"],
-        false
+        response["result"]["capabilities"]["tools"]["listChanged"],
+        true
     );
 }
 
@@ -45,8 +45,16 @@ async fn test_tools_list() {
 
     let tools = &response["result"]["tools"];
     assert!(tools.is_array());
-    assert_eq!(tools.as_array().unwrap().len(), 3);
+    // Generated per-task tools only appear where angreal is installed
+    let builtin: Vec<_> = tools
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter(|tool| !tool["name"].as_str().unwrap().starts_with("angreal__"))
+        .collect();
+    assert_eq!(builtin.len(), 8);
     assert_eq!(tools[0]["name"], "angreal_check");
+    assert_eq!(tools[3]["name"], "angreal_run_output");
 }
 
 #[tokio::test]
@@ -78,6 +86,1469 @@ async fn test_malformed_json() {
     assert_eq!(response["error"]["message"], "Parse error");
 }
 
+#[tokio::test]
+async fn test_angreal_run_declares_timeout() {
+    let request = json!({
+        "jsonrpc": "2.0",
+        "id": 4,
+        "method": "tools/list"
+    });
+
+    let response_str = handle_request_string(request.to_string()).await;
+    let response: serde_json::Value = serde_json::from_str(&response_str).unwrap();
+
+    let run_tool = response["result"]["tools"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|tool| tool["name"] == "angreal_run")
+        .unwrap();
+    assert_eq!(
+        run_tool["inputSchema"]["properties"]["timeout_seconds"]["type"],
+        "number"
+    );
+}
+
+#[tokio::test]
+async fn test_failing_task_is_tool_error() {
+    let request = json!({
+        "jsonrpc": "2.0",
+        "id": 3,
+        "method": "tools/call",
+        "params": {
+            "name": "angreal_run",
+            "arguments": {"command": "no-such-angreal-command"}
+        }
+    });
+
+    let response_str = handle_request_string(request.to_string()).await;
+    let response: serde_json::Value = serde_json::from_str(&response_str).unwrap();
+
+    // Whether angreal is missing or rejects the command, the model gets to
+    // see why instead of a protocol error
+    assert!(response.get("error").is_none());
+    assert_eq!(response["result"]["isError"], true);
+    assert!(response["result"]["content"][0]["text"]
+        .as_str()
+        .unwrap()
+        .starts_with("$ angreal no-such-angreal-command"));
+}
+
+#[test]
+fn test_parse_timeout_seconds() {
+    use angreal_mcp::mcp::parse_timeout_seconds;
+    use std::time::Duration;
+
+    assert_eq!(
+        parse_timeout_seconds(&json!(1.5)).unwrap(),
+        Duration::from_millis(1500)
+    );
+    assert!(parse_timeout_seconds(&json!(0)).is_err());
+    assert!(parse_timeout_seconds(&json!(-3)).is_err());
+    assert!(parse_timeout_seconds(&json!("10")).is_err());
+    assert!(parse_timeout_seconds(&json!(1e300)).is_err());
+}
+
+#[tokio::test]
+async fn test_cancellable_request_completes() {
+    use angreal_mcp::mcp::{JsonRpcRequest, McpServer};
+    use std::sync::Arc;
+
+    let server = Arc::new(McpServer::new());
+    let request: JsonRpcRequest = serde_json::from_value(json!({
+        "jsonrpc": "2.0",
+        "id": 5,
+        "method": "ping"
+    }))
+    .unwrap();
+
+    let handler = server.handle_cancellable(request);
+    // Cancelling an id that isn't in flight is ignored
+    server.handle_cancelled(Some(json!({"requestId": 99})));
+
+    let response = handler.await.expect("request was not cancelled").unwrap();
+    assert_eq!(response.id, Some(json!(5)));
+    assert_eq!(response.result, Some(json!({})));
+}
+
+#[tokio::test]
+async fn test_lifecycle() {
+    use angreal_mcp::mcp::{JsonRpcRequest, McpServer, SessionState};
+
+    let server = McpServer::new();
+    let request =
+        |value: serde_json::Value| -> JsonRpcRequest { serde_json::from_value(value).unwrap() };
+    assert_eq!(server.state(), SessionState::Uninitialized);
+
+    // Ping works at any time, tools only after initialize
+    let response = server
+        .handle_request(request(
+            json!({"jsonrpc": "2.0", "id": 1, "method": "ping"}),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.result, Some(json!({})));
+
+    let response = server
+        .handle_request(request(
+            json!({"jsonrpc": "2.0", "id": 2, "method": "tools/list"}),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.error.unwrap().code, -32002);
+
+    let initialize = json!({
+        "jsonrpc": "2.0",
+        "id": 3,
+        "method": "initialize",
+        "params": {"protocolVersion": "2025-06-18", "capabilities": {}}
+    });
+    let response = server
+        .handle_request(request(initialize.clone()))
+        .await
+        .unwrap();
+    assert!(response.error.is_none());
+    assert_eq!(server.state(), SessionState::Initializing);
+
+    // A second initialize is rejected
+    let response = server.handle_request(request(initialize)).await.unwrap();
+    assert_eq!(response.error.unwrap().code, -32600);
+
+    server.handle_notification(request(
+        json!({"jsonrpc": "2.0", "method": "notifications/initialized"}),
+    ));
+    assert_eq!(server.state(), SessionState::Ready);
+
+    server.begin_shutdown();
+    let response = server
+        .handle_request(request(
+            json!({"jsonrpc": "2.0", "id": 4, "method": "tools/list"}),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.error.unwrap().code, -32000);
+}
+
+#[test]
+fn test_notification_serialization() {
+    use angreal_mcp::mcp::{JsonRpcMessage, JsonRpcNotification};
+
+    let message: JsonRpcMessage = JsonRpcNotification {
+        jsonrpc: "2.0".to_string(),
+        method: "notifications/progress".to_string(),
+        params: Some(json!({"progressToken": "abc", "progress": 1, "message": "line"})),
+    }
+    .into();
+
+    let value = serde_json::to_value(&message).unwrap();
+    assert_eq!(value["method"], "notifications/progress");
+    assert_eq!(value["params"]["progressToken"], "abc");
+    assert!(value.get("id").is_none());
+}
+
+#[tokio::test]
+async fn test_notifications_are_not_answered() {
+    let responses = run_server_session(
+        None,
+        &[
+            json!({
+                "jsonrpc": "2.0",
+                "id": 0,
+                "method": "initialize",
+                "params": {"protocolVersion": "2025-06-18", "capabilities": {}}
+            }),
+            json!({"jsonrpc": "2.0", "method": "notifications/initialized"}),
+            json!({"jsonrpc": "2.0", "method": "notifications/roots/list_changed"}),
+            json!({"jsonrpc": "2.0", "method": "notifications/unknown"}),
+            json!({"jsonrpc": "2.0", "id": 6, "method": "tools/list"}),
+        ],
+    )
+    .await;
+
+    assert_eq!(responses.len(), 2);
+    let tools_list = responses.iter().find(|r| r["id"] == 6).unwrap();
+    assert!(tools_list["result"]["tools"].is_array());
+}
+
+#[test]
+fn test_protocol_version_negotiation() {
+    use angreal_mcp::mcp::ProtocolVersion;
+
+    assert_eq!(
+        ProtocolVersion::negotiate("2025-03-26").as_str(),
+        "2025-03-26"
+    );
+    assert_eq!(
+        ProtocolVersion::negotiate("2024-11-05").as_str(),
+        "2024-11-05"
+    );
+    // Unknown revisions fall back to the closest older one we support
+    assert_eq!(
+        ProtocolVersion::negotiate("2025-05-01").as_str(),
+        "2025-03-26"
+    );
+    assert_eq!(
+        ProtocolVersion::negotiate("2099-01-01"),
+        ProtocolVersion::LATEST
+    );
+    // Anything older than we know gets our latest
+    assert_eq!(ProtocolVersion::negotiate("1.0"), ProtocolVersion::LATEST);
+}
+
+#[tokio::test]
+async fn test_tool_fields_follow_negotiated_version() {
+    for (version, has_annotations, has_structured_output) in [
+        ("2024-11-05", false, false),
+        ("2025-03-26", true, false),
+        ("2025-06-18", true, true),
+    ] {
+        let responses = run_server_session(
+            None,
+            &[
+                json!({
+                    "jsonrpc": "2.0",
+                    "id": 1,
+                    "method": "initialize",
+                    "params": {"protocolVersion": version, "capabilities": {}}
+                }),
+                json!({"jsonrpc": "2.0", "method": "notifications/initialized"}),
+                json!({"jsonrpc": "2.0", "id": 2, "method": "tools/list"}),
+            ],
+        )
+        .await;
+
+        let initialize = responses.iter().find(|r| r["id"] == 1).unwrap();
+        assert_eq!(initialize["result"]["protocolVersion"], version);
+
+        let tools = &responses.iter().find(|r| r["id"] == 2).unwrap()["result"]["tools"];
+        let run_tool = &tools[2];
+        assert_eq!(run_tool["name"], "angreal_run");
+        assert_eq!(run_tool.get("annotations").is_some(), has_annotations);
+        assert_eq!(run_tool.get("title").is_some(), has_structured_output);
+        assert_eq!(
+            run_tool.get("outputSchema").is_some(),
+            has_structured_output
+        );
+    }
+}
+
+fn sample_tree() -> angreal_mcp::angreal::CommandTree {
+    serde_json::from_value(json!({
+        "commands": [
+            {
+                "name": "command-1",
+                "path": "call-testing command-1",
+                "group": "call-testing",
+                "description": "First test command",
+                "arguments": [
+                    {"name": "option", "flag": "--option", "type": "flag", "required": true, "help": "Required option flag"}
+                ]
+            },
+            {
+                "name": "command-2",
+                "path": "call-testing command-2",
+                "group": "call-testing",
+                "description": "Second test command",
+                "arguments": [
+                    {"name": "parameter", "flag": "--parameter", "type": "positional", "required": true, "help": "Required parameter"}
+                ]
+            },
+            {
+                "name": "command-3",
+                "path": "call-testing command-3",
+                "group": "call-testing",
+                "description": "Third test command",
+                "arguments": [
+                    {"name": "filename", "type": "positional", "required": true, "help": "Required filename"},
+                    {"name": "yell", "flag": "--verbose", "type": "flag", "required": false, "help": "Verbose output"}
+                ]
+            }
+        ]
+    }))
+    .unwrap()
+}
+
+#[test]
+fn test_command_tree_model() {
+    use angreal_mcp::angreal::{ArgumentKind, CommandTree};
+
+    let tree = sample_tree();
+    assert_eq!(
+        tree.commands[2].signature(),
+        "call-testing command-3 <filename> [--verbose]"
+    );
+    assert_eq!(tree.commands[1].arguments[0].kind(), ArgumentKind::Option);
+
+    let groups = tree.groups();
+    assert_eq!(groups.len(), 1);
+    assert_eq!(groups[0].0, "call-testing");
+    assert_eq!(groups[0].1.len(), 3);
+
+    // Unknown fields, nulls and missing paths are tolerated
+    let tree = CommandTree::from_json(
+        r#"{"version": "2.0", "commands": [
+            {"name": "build", "group": null, "description": null, "hidden": false,
+             "arguments": [{"name": "target", "type": "choice", "required": null}]}
+        ]}"#,
+    )
+    .unwrap();
+    assert_eq!(tree.commands[0].path(), "build");
+    assert_eq!(tree.commands[0].signature(), "build [<target>]");
+    assert_eq!(tree.groups()[0].0, "");
+}
+
+#[test]
+fn test_task_tools_from_tree() {
+    use angreal_mcp::mcp::task_tools;
+
+    let tools = task_tools(&sample_tree());
+    assert_eq!(tools.len(), 3);
+
+    assert_eq!(tools[0].name, "angreal__call-testing__command-1");
+    assert_eq!(
+        tools[0].input_schema["properties"]["option"]["type"],
+        "boolean"
+    );
+    assert_eq!(tools[0].input_schema["required"], json!(["option"]));
+
+    assert_eq!(tools[1].name, "angreal__call-testing__command-2");
+    assert_eq!(
+        tools[1].input_schema["properties"]["parameter"]["type"],
+        "string"
+    );
+
+    assert_eq!(tools[2].input_schema["required"], json!(["filename"]));
+    assert_eq!(
+        tools[2].output_schema.as_ref().unwrap()["required"][0],
+        "exit_code"
+    );
+}
+
+#[test]
+fn test_task_argv() {
+    use angreal_mcp::mcp::task_argv;
+
+    let tree = sample_tree();
+    let commands = &tree.commands;
+
+    assert_eq!(
+        task_argv(&commands[0], Some(&json!({"option": true}))).unwrap(),
+        vec!["--option"]
+    );
+    assert_eq!(
+        task_argv(&commands[1], Some(&json!({"parameter": "value"}))).unwrap(),
+        vec!["--parameter", "value"]
+    );
+    // Options first, positionals after
+    assert_eq!(
+        task_argv(
+            &commands[2],
+            Some(&json!({"filename": "notes.txt", "yell": true}))
+        )
+        .unwrap(),
+        vec!["--verbose", "notes.txt"]
+    );
+
+    let missing = task_argv(&commands[1], None).unwrap_err();
+    assert!(missing.details.contains("parameter"));
+    assert_eq!(missing.pointer, "/arguments/parameter");
+    let unknown =
+        task_argv(&commands[2], Some(&json!({"filename": "a", "loud": true}))).unwrap_err();
+    assert!(unknown.details.contains("loud"));
+    assert_eq!(unknown.pointer, "/arguments/loud");
+}
+
+#[test]
+fn test_check_args() {
+    let tree = sample_tree();
+    let argv = |words: &[&str]| words.iter().map(|w| w.to_string()).collect::<Vec<_>>();
+
+    let full = argv(&["call-testing", "command-3", "notes.txt", "--verbose"]);
+    let (cmd, rest) = tree.resolve(&full).unwrap();
+    assert_eq!(cmd.name, "command-3");
+    assert_eq!(rest, &full[2..]);
+    assert!(cmd.check_args(rest).is_ok());
+    assert!(tree.resolve(&argv(&["init", "template"])).is_none());
+
+    let command_1 = &tree.commands[0];
+    let command_2 = &tree.commands[1];
+    let command_3 = &tree.commands[2];
+    assert!(command_2.check_args(&argv(&["--parameter=x"])).is_ok());
+    assert!(command_1.check_args(&argv(&["--help"])).is_ok());
+
+    let errors = command_2.check_args(&[]).unwrap_err();
+    assert_eq!(errors.missing, vec!["--parameter <value>"]);
+
+    let errors = command_1
+        .check_args(&argv(&["--option", "--loud", "extra"]))
+        .unwrap_err();
+    assert!(errors.missing.is_empty());
+    assert_eq!(errors.unknown, vec!["--loud"]);
+    assert_eq!(errors.unexpected, vec!["extra"]);
+
+    let errors = command_2.check_args(&argv(&["--parameter"])).unwrap_err();
+    assert!(errors.to_string().contains("--parameter requires a value"));
+
+    let errors = command_3.check_args(&argv(&["a", "b"])).unwrap_err();
+    assert_eq!(errors.unexpected, vec!["b"]);
+}
+
+#[test]
+fn test_find_project_root() {
+    use angreal_mcp::angreal::find_project_root;
+
+    let dir = tempfile::tempdir().unwrap();
+    let nested = dir.path().join("src/deeply/nested");
+    std::fs::create_dir_all(&nested).unwrap();
+    assert_eq!(find_project_root(&nested), None);
+
+    std::fs::create_dir(dir.path().join(".angreal")).unwrap();
+    assert_eq!(find_project_root(&nested).as_deref(), Some(dir.path()));
+    assert_eq!(find_project_root(dir.path()).as_deref(), Some(dir.path()));
+}
+
+/// Writes a stand-in `angreal` to `dir` whose `tree --json` prints
+/// `.angreal/tree.json` if there is one, or else lists a command per
+/// `.angreal/task_<name>.py` and fails if any task file contains `raise`.
+/// Each `tree` call is logged to `<dir>/angreal.log`.
+#[cfg(unix)]
+fn tree_stub(dir: &std::path::Path) -> std::path::PathBuf {
+    use std::os::unix::fs::PermissionsExt;
+
+    let script = dir.join("angreal");
+    std::fs::write(
+        &script,
+        format!(
+            r#"#!/bin/sh
+echo "$@" >> "{}"
+if [ -f .angreal/tree.json ]; then
+    cat .angreal/tree.json
+    exit 0
+fi
+if grep -q raise .angreal/*.py 2>/dev/null; then
+    echo "SyntaxError: invalid syntax" >&2
+    exit 1
+fi
+printf '{{"commands": ['
+sep=
+for file in .angreal/task_*.py; do
+    [ -f "$file" ] || continue
+    name=$(basename "$file" .py)
+    printf '%s{{"name": "%s"}}' "$sep" "${{name#task_}}"
+    sep=,
+done
+echo ']}}'
+"#,
+            dir.join("angreal.log").display()
+        ),
+    )
+    .unwrap();
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+    script
+}
+
+/// How many times the [`tree_stub`] in `dir` has been run.
+#[cfg(unix)]
+fn tree_runs(dir: &std::path::Path) -> usize {
+    std::fs::read_to_string(dir.join("angreal.log")).map_or(0, |log| log.lines().count())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_tree_cache_invalidation() {
+    use angreal_mcp::angreal::TreeCache;
+
+    let bin = tempfile::tempdir().unwrap();
+    let project = tempfile::tempdir().unwrap();
+    let angreal = project.path().join(".angreal");
+    std::fs::create_dir(&angreal).unwrap();
+    std::fs::write(angreal.join("task_build.py"), "").unwrap();
+    let nested = project.path().join("src/nested");
+    std::fs::create_dir_all(&nested).unwrap();
+
+    let cache = TreeCache::new()
+        .with_program(tree_stub(bin.path()))
+        .in_dir(&nested);
+    let names = |snapshot: &angreal_mcp::angreal::TreeSnapshot| -> Vec<String> {
+        snapshot
+            .tree
+            .commands
+            .iter()
+            .map(|c| c.name.clone())
+            .collect()
+    };
+
+    let first = cache.load().await.unwrap();
+    assert!(!first.from_cache);
+    assert_eq!(first.root, project.path());
+    assert_eq!(names(&first), ["build"]);
+    assert_eq!(tree_runs(bin.path()), 1);
+
+    // Unchanged task files are served without running angreal again
+    let second = cache.load().await.unwrap();
+    assert!(second.from_cache);
+    assert_eq!(names(&second), ["build"]);
+    assert_eq!(tree_runs(bin.path()), 1);
+
+    // Adding, modifying or removing a task file reloads the tree
+    std::fs::create_dir(angreal.join("sub")).unwrap();
+    std::fs::write(angreal.join("sub/helpers.py"), "").unwrap();
+    assert!(!cache.load().await.unwrap().from_cache);
+    assert_eq!(tree_runs(bin.path()), 2);
+    std::fs::write(angreal.join("task_test.py"), "").unwrap();
+    assert_eq!(names(&cache.load().await.unwrap()), ["build", "test"]);
+    assert_eq!(tree_runs(bin.path()), 3);
+    std::fs::write(angreal.join("task_test.py"), "# runs the tests\n").unwrap();
+    assert!(!cache.load().await.unwrap().from_cache);
+    assert_eq!(tree_runs(bin.path()), 4);
+    std::fs::remove_file(angreal.join("task_test.py")).unwrap();
+    assert_eq!(names(&cache.load().await.unwrap()), ["build"]);
+    assert_eq!(tree_runs(bin.path()), 5);
+
+    // So does angreal.toml, but other files don't
+    std::fs::write(project.path().join("angreal.toml"), "").unwrap();
+    assert!(!cache.load().await.unwrap().from_cache);
+    assert_eq!(tree_runs(bin.path()), 6);
+    std::fs::write(angreal.join("notes.txt"), "").unwrap();
+    std::fs::write(nested.join("main.py"), "").unwrap();
+    assert!(cache.load().await.unwrap().from_cache);
+    assert_eq!(tree_runs(bin.path()), 6);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_tree_cache_failures() {
+    use angreal_mcp::angreal::{AngrealError, TreeCache};
+
+    let bin = tempfile::tempdir().unwrap();
+    let project = tempfile::tempdir().unwrap();
+    let angreal = project.path().join(".angreal");
+    std::fs::create_dir(&angreal).unwrap();
+    std::fs::write(angreal.join("task_build.py"), "raise").unwrap();
+
+    // A broken project is remembered until its task files change
+    let cache = TreeCache::new()
+        .with_program(tree_stub(bin.path()))
+        .in_dir(project.path());
+    for _ in 0..2 {
+        let error = cache.load().await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<AngrealError>(),
+            Some(AngrealError::ExecutionFailed(details)) if details.contains("SyntaxError")
+        ));
+    }
+    assert_eq!(tree_runs(bin.path()), 1);
+    std::fs::write(angreal.join("task_build.py"), "").unwrap();
+    assert!(cache.load().await.is_ok());
+    assert_eq!(tree_runs(bin.path()), 2);
+
+    // A missing angreal is looked for again every time
+    let program = bin.path().join("later/angreal");
+    let cache = TreeCache::new()
+        .with_program(&program)
+        .in_dir(project.path());
+    assert!(matches!(
+        cache
+            .load()
+            .await
+            .unwrap_err()
+            .downcast_ref::<AngrealError>(),
+        Some(AngrealError::NotInstalled)
+    ));
+    std::fs::create_dir(bin.path().join("later")).unwrap();
+    std::fs::rename(bin.path().join("angreal"), &program).unwrap();
+    assert!(cache.load().await.is_ok());
+    assert_eq!(tree_runs(bin.path()), 3);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_run_args_follow_cwd_project() {
+    use angreal_mcp::angreal::TreeCache;
+    use angreal_mcp::mcp::McpServer;
+    use angreal_mcp::transport::{duplex, serve, Transport};
+
+    // The root project's `build` takes no arguments, the nested one's does
+    let bin = tempfile::tempdir().unwrap();
+    let project = tempfile::tempdir().unwrap();
+    let nested = project.path().join("packages/core");
+    for (dir, arguments) in [
+        (project.path().to_path_buf(), json!([])),
+        (
+            nested.clone(),
+            json!([{"name": "release", "flag": "--release", "type": "flag"}]),
+        ),
+    ] {
+        std::fs::create_dir_all(dir.join(".angreal")).unwrap();
+        let tree = json!({"commands": [{"name": "build", "arguments": arguments}]});
+        std::fs::write(dir.join(".angreal/tree.json"), tree.to_string()).unwrap();
+    }
+    let cache = TreeCache::new()
+        .with_program(tree_stub(bin.path()))
+        .in_dir(project.path());
+
+    let (server_end, mut client) = duplex();
+    let session = tokio::spawn(serve(
+        McpServer::new().with_tree_cache(cache),
+        server_end,
+        std::future::pending(),
+    ));
+    let [initialize, initialized] = handshake();
+    client.send(initialize.to_string()).await.unwrap();
+    client.receive().await.unwrap().unwrap();
+    client.send(initialized.to_string()).await.unwrap();
+
+    let mut responses = Vec::new();
+    for (id, cwd) in [(1, "."), (2, "packages/core")] {
+        let call = json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": "tools/call",
+            "params": {
+                "name": "angreal_run",
+                "arguments": {"command": "build", "args": ["--release"], "cwd": cwd}
+            }
+        });
+        client.send(call.to_string()).await.unwrap();
+        let response = client.receive().await.unwrap().unwrap();
+        responses.push(serde_json::from_str::<serde_json::Value>(&response).unwrap());
+    }
+    assert_eq!(responses[0]["error"]["code"], -32602);
+    assert_eq!(
+        responses[0]["error"]["data"]["unknown"],
+        json!(["--release"])
+    );
+    assert!(responses[1].get("error").is_none(), "{}", responses[1]);
+
+    session.abort();
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_initialize_lists_commands_by_group() {
+    use angreal_mcp::angreal::TreeCache;
+    use angreal_mcp::mcp::McpServer;
+    use angreal_mcp::transport::{duplex, serve, Transport};
+
+    let bin = tempfile::tempdir().unwrap();
+    let project = tempfile::tempdir().unwrap();
+    std::fs::create_dir(project.path().join(".angreal")).unwrap();
+    let tree = json!({"commands": [
+        {"name": "build", "group": "docs", "description": "Build the docs"},
+        {"name": "test", "description": "Run the tests"},
+        {"name": "serve", "group": "docs", "description": "Serve the docs"}
+    ]});
+    std::fs::write(project.path().join(".angreal/tree.json"), tree.to_string()).unwrap();
+    let cache = TreeCache::new()
+        .with_program(tree_stub(bin.path()))
+        .in_dir(project.path());
+
+    let (server_end, mut client) = duplex();
+    let session = tokio::spawn(serve(
+        McpServer::new().with_tree_cache(cache),
+        server_end,
+        std::future::pending(),
+    ));
+    let [initialize, _] = handshake();
+    client.send(initialize.to_string()).await.unwrap();
+    let response = client.receive().await.unwrap().unwrap();
+    let response: serde_json::Value = serde_json::from_str(&response).unwrap();
+    assert_eq!(
+        response["result"]["serverInfo"]["context"]["availableCommands"],
+        json!([
+            "docs build - Build the docs",
+            "docs serve - Serve the docs",
+            "test - Run the tests"
+        ])
+    );
+
+    session.abort();
+}
+
+#[test]
+fn test_resolve_working_dir() {
+    use angreal_mcp::angreal::resolve_working_dir;
+    use std::path::Path;
+
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("project");
+    std::fs::create_dir_all(root.join(".angreal")).unwrap();
+    std::fs::create_dir_all(root.join("packages/core")).unwrap();
+    std::fs::create_dir(dir.path().join("elsewhere")).unwrap();
+    let root = root.canonicalize().unwrap();
+
+    assert_eq!(
+        resolve_working_dir(&root, Path::new("packages/core")).unwrap(),
+        root.join("packages/core")
+    );
+    assert_eq!(
+        resolve_working_dir(&root.join("packages"), Path::new("../packages/core")).unwrap(),
+        root.join("packages/core")
+    );
+    assert_eq!(
+        resolve_working_dir(&root.join("packages"), &root).unwrap(),
+        root
+    );
+
+    for outside in ["..", "../elsewhere", "/"] {
+        let err = resolve_working_dir(&root, Path::new(outside)).unwrap_err();
+        assert!(err.contains("outside the project root"), "{}", err);
+    }
+    assert!(resolve_working_dir(&root, Path::new("missing")).is_err());
+    assert!(resolve_working_dir(&root, Path::new(".angreal/../packages/core")).is_ok());
+    assert!(resolve_working_dir(&dir.path().join("elsewhere"), Path::new(".")).is_err());
+}
+
+#[tokio::test]
+async fn test_background_job_lifecycle() {
+    use angreal_mcp::angreal::RunOptions;
+    use angreal_mcp::jobs::{JobRegistry, JobState, OutputOffset, MAX_FINISHED_JOBS};
+
+    let jobs = JobRegistry::new();
+    let job = jobs
+        .start("no-such-angreal-command", vec![], RunOptions::default())
+        .await;
+    assert_eq!(job.id, "job-1");
+    assert!(jobs.get("job-1").is_some());
+
+    // Fails either to spawn angreal or inside it, but always finishes
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(30);
+    while job.state() == JobState::Running {
+        assert!(std::time::Instant::now() < deadline, "job never finished");
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    assert_ne!(
+        job.state(),
+        JobState::Exited {
+            exit_code: Some(0),
+            signal: None
+        }
+    );
+    assert!(!job.kill(), "finished jobs can't be killed");
+
+    let status = job.status();
+    assert_eq!(status["job_id"], "job-1");
+    assert_eq!(status["command"], "no-such-angreal-command");
+
+    let chunk = job.read_output(OutputOffset::Line(0), 10);
+    assert_eq!(chunk.dropped_lines, 0);
+    assert_eq!(chunk.next_line, status["output_lines"]);
+    assert_eq!(chunk.next_byte, status["output_bytes"]);
+
+    // Only the most recently finished jobs are kept
+    for _ in 0..MAX_FINISHED_JOBS {
+        let job = jobs
+            .start("no-such-angreal-command", vec![], RunOptions::default())
+            .await;
+        while job.state() == JobState::Running {
+            assert!(std::time::Instant::now() < deadline, "job never finished");
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+    }
+    jobs.start("no-such-angreal-command", vec![], RunOptions::default())
+        .await;
+    assert!(jobs.get("job-1").is_none());
+    assert!(jobs.get("job-2").is_some());
+    assert_eq!(jobs.list().len(), MAX_FINISHED_JOBS + 1);
+}
+
+#[test]
+fn test_output_truncation_and_paging() {
+    use angreal_mcp::angreal::OutputStream;
+    use angreal_mcp::runs::{truncate_output, RunStore, MAX_STORED_RUNS, MAX_STORED_RUN_BYTES};
+
+    let runs = RunStore::new();
+    let text: String = (0..1000).map(|i| format!("line {}\n", i)).collect();
+    let run = runs.store(vec!["angreal".to_string()], text, "oops\n".to_string());
+    assert_eq!(run.id, "run-1");
+
+    let (short, truncated) = truncate_output(&run, OutputStream::Stdout, 100);
+    assert!(truncated);
+    assert!(short.starts_with("line 0\n"));
+    assert!(short.ends_with("line 999\n"));
+    assert!(short.contains("the full output with angreal_run_output using run_id \"run-1\""));
+    assert!(short.len() < 300);
+
+    assert_eq!(
+        truncate_output(&run, OutputStream::Stderr, 100),
+        ("oops\n".to_string(), false)
+    );
+    assert!(
+        !truncate_output(&run, OutputStream::Stdout, 0).1,
+        "0 disables truncation"
+    );
+
+    let page = run.page(OutputStream::Stdout, 10, 2, None);
+    assert_eq!(page.output, "    11: line 10\n    12: line 11\n");
+    assert_eq!(page.next_offset, 12);
+    assert!(page.more);
+
+    let page = run.page(OutputStream::Stdout, 0, 50, Some("line 99"));
+    assert_eq!(page.total_lines, 11);
+    assert!(!page.more);
+
+    assert_eq!(run.page(OutputStream::Stderr, 0, 10, None).total_lines, 1);
+
+    // Stored output is capped too, and a stream with little output leaves
+    // its share to the other
+    let line = "x".repeat(99) + "\n";
+    let huge = line.repeat(MAX_STORED_RUN_BYTES / 100 + 1000);
+    let run = runs.store(vec![], huge.clone(), "oops\n".to_string());
+    assert_eq!(run.stderr, "oops\n");
+    assert!(run.stdout.len() <= MAX_STORED_RUN_BYTES);
+    assert!(run.stdout.len() > MAX_STORED_RUN_BYTES - 1000);
+    assert!(run.stdout.contains("not kept"));
+    assert!(run.stdout_dropped >= 100_000);
+    assert_eq!(run.stderr_dropped, 0);
+    let (short, _) = truncate_output(&run, OutputStream::Stdout, 1000);
+    assert!(short.contains(&format!("itself missing {} bytes", run.stdout_dropped)));
+
+    let run = runs.store(vec![], huge.clone(), huge);
+    assert!(run.stdout.len() <= MAX_STORED_RUN_BYTES / 2 + 200);
+    assert!(run.stderr.len() <= MAX_STORED_RUN_BYTES / 2 + 200);
+    assert!(run.stderr_dropped > 0);
+
+    for _ in 0..MAX_STORED_RUNS {
+        runs.store(vec![], String::new(), String::new());
+    }
+    assert!(runs.get("run-1").is_none(), "oldest run is evicted");
+    assert!(runs.get("run-4").is_some());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_ran_out_of_input() {
+    use angreal_mcp::angreal::CommandOutput;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+
+    let output = |stderr: &str, code: i32| CommandOutput {
+        argv: vec!["angreal".to_string(), "init".to_string()],
+        stdout: "Project name: ".to_string(),
+        stderr: stderr.to_string(),
+        status: Some(ExitStatus::from_raw(code << 8)),
+        timed_out: false,
+        duration: std::time::Duration::ZERO,
+    };
+
+    assert!(output("EOFError: EOF when reading a line\n", 1).ran_out_of_input());
+    assert!(output("Aborted!\n", 1).ran_out_of_input());
+    assert!(!output("Error: no such template\n", 1).ran_out_of_input());
+    assert!(
+        !output("EOFError\n", 0).ran_out_of_input(),
+        "only failures count"
+    );
+}
+
+#[test]
+fn test_clean_terminal_output() {
+    use angreal_mcp::terminal::clean_output;
+
+    assert_eq!(
+        clean_output("\x1b[1;32m   Compiling\x1b[0m foo v0.1.0\n"),
+        "   Compiling foo v0.1.0\n"
+    );
+    // Progress bars keep only their final state
+    assert_eq!(
+        clean_output("[    ] 0%\r[==  ] 50%\r[====] 100%\ndone\n"),
+        "[====] 100%\ndone\n"
+    );
+    assert_eq!(clean_output("Downloading 50%\r\x1b[2KDone\n"), "Done\n");
+    assert_eq!(clean_output("Downloading 50%\r\x1b[KDone"), "Done");
+    assert_eq!(clean_output("abc\x08\x08X\n"), "aXc\n");
+    // CRLF line endings are not overwrites
+    assert_eq!(clean_output("one\r\ntwo\r\n"), "one\ntwo\n");
+    // Window titles, hyperlinks and cursor movement
+    assert_eq!(
+        clean_output("\x1b]0;title\x07\x1b]8;;http://x\x1b\\link\x1b]8;;\x1b\\\x1b[1A\x1b(B!"),
+        "link!"
+    );
+    // 8-bit CSI
+    assert_eq!(clean_output("a\x1b[31mb\u{9b}0mc"), "abc");
+}
+
+#[tokio::test]
+async fn test_serve_over_memory_transport() {
+    use angreal_mcp::mcp::McpServer;
+    use angreal_mcp::transport::{duplex, serve, Ending, Transport};
+
+    let (server_end, mut client) = duplex();
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let session = tokio::spawn(serve(McpServer::new(), server_end, async move {
+        let _ = stopped.await;
+    }));
+
+    let messages = [
+        json!({
+            "jsonrpc": "2.0",
+            "id": 0,
+            "method": "initialize",
+            "params": {"protocolVersion": "2025-06-18", "capabilities": {}}
+        })
+        .to_string(),
+        json!({"jsonrpc": "2.0", "method": "notifications/initialized"}).to_string(),
+        "{not json".to_string(),
+        json!({"jsonrpc": "2.0", "id": 1, "method": "ping"}).to_string(),
+    ];
+    for message in messages {
+        client.send(message).await.unwrap();
+    }
+
+    let mut received = Vec::new();
+    while received.len() < 3 {
+        let message = client.receive().await.unwrap().unwrap();
+        received.push(serde_json::from_str::<serde_json::Value>(&message).unwrap());
+    }
+    assert_eq!(received[0]["id"], 0);
+    assert_eq!(received[0]["result"]["protocolVersion"], "2025-06-18");
+    let parse_error = received.iter().find(|m| m["id"].is_null()).unwrap();
+    assert_eq!(parse_error["error"]["code"], -32700);
+    assert!(received
+        .iter()
+        .any(|m| m["id"] == 1 && m["result"] == json!({})));
+
+    // Closing the client ends the session, which closes the server's end
+    client.close().await.unwrap();
+    assert_eq!(session.await.unwrap().unwrap(), Ending::Closed);
+    assert!(client.receive().await.unwrap().is_none());
+    drop(stop);
+
+    // A shutdown ends the session even while the client stays connected
+    let (server_end, _client) = duplex();
+    let ending = serve(McpServer::new(), server_end, async {}).await.unwrap();
+    assert_eq!(ending, Ending::Terminated);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_tools_list_changed_notification() {
+    use angreal_mcp::angreal::TreeCache;
+    use angreal_mcp::mcp::{McpServer, TOOL_WATCH_INTERVAL};
+    use angreal_mcp::transport::{duplex, serve, Transport};
+
+    let bin = tempfile::tempdir().unwrap();
+    let project = tempfile::tempdir().unwrap();
+    let angreal = project.path().join(".angreal");
+    std::fs::create_dir(&angreal).unwrap();
+    std::fs::write(angreal.join("task_build.py"), "").unwrap();
+    let cache = TreeCache::new()
+        .with_program(tree_stub(bin.path()))
+        .in_dir(project.path());
+
+    let (server_end, mut client) = duplex();
+    let session = tokio::spawn(serve(
+        McpServer::new().with_tree_cache(cache),
+        server_end,
+        std::future::pending(),
+    ));
+    let [initialize, initialized] = handshake();
+    let list = json!({"jsonrpc": "2.0", "id": 1, "method": "tools/list"});
+    for message in [initialize, initialized, list] {
+        client.send(message.to_string()).await.unwrap();
+    }
+    let mut tools = None;
+    while tools.is_none() {
+        let message = client.receive().await.unwrap().unwrap();
+        let message: serde_json::Value = serde_json::from_str(&message).unwrap();
+        if message["id"] == 1 {
+            tools = Some(message["result"]["tools"].clone());
+        }
+    }
+    assert!(tools
+        .unwrap()
+        .as_array()
+        .unwrap()
+        .iter()
+        .any(|tool| tool["name"] == "angreal__build"));
+
+    // Nothing is sent while the tasks stay the same, and a change is
+    // announced once, not on every check after it
+    let quiet = TOOL_WATCH_INTERVAL * 2 + TOOL_WATCH_INTERVAL / 2;
+    assert!(tokio::time::timeout(quiet, client.receive()).await.is_err());
+    std::fs::write(angreal.join("task_test.py"), "").unwrap();
+    let message = tokio::time::timeout(TOOL_WATCH_INTERVAL * 5, client.receive())
+        .await
+        .expect("the change is announced")
+        .unwrap()
+        .unwrap();
+    let message: serde_json::Value = serde_json::from_str(&message).unwrap();
+    assert_eq!(message["method"], "notifications/tools/list_changed");
+    assert!(tokio::time::timeout(quiet, client.receive()).await.is_err());
+
+    session.abort();
+}
+
+#[tokio::test]
+async fn test_line_transport_framing() {
+    use angreal_mcp::transport::{LineTransport, Transport};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+
+    let (client, server) = tokio::io::duplex(1024);
+    let (server_read, server_write) = tokio::io::split(server);
+    let (mut client_read, mut client_write) = tokio::io::split(client);
+    let mut transport = LineTransport::new(BufReader::new(server_read), server_write);
+
+    client_write
+        .write_all(b"{\"a\":1}\n\n  \r\n{\"b\":")
+        .await
+        .unwrap();
+    assert_eq!(transport.receive().await.unwrap().unwrap(), r#"{"a":1}"#);
+
+    // Half a message is kept when a read is abandoned
+    let pending = tokio::time::timeout(std::time::Duration::from_millis(50), transport.receive());
+    assert!(pending.await.is_err());
+    client_write.write_all(b"2}\r\n{\"c\":3}").await.unwrap();
+    assert_eq!(transport.receive().await.unwrap().unwrap(), r#"{"b":2}"#);
+    client_write.shutdown().await.unwrap();
+    assert_eq!(transport.receive().await.unwrap().unwrap(), r#"{"c":3}"#);
+    assert!(transport.receive().await.unwrap().is_none());
+
+    transport.send(r#"{"ok":true}"#.to_string()).await.unwrap();
+    transport.close().await.unwrap();
+    let mut written = String::new();
+    client_read.read_to_string(&mut written).await.unwrap();
+    assert_eq!(written, "{\"ok\":true}\n");
+}
+
+#[tokio::test]
+async fn test_http_sessions() {
+    use angreal_mcp::http::HttpServer;
+    use angreal_mcp::mcp::McpServer;
+    use std::sync::Arc;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let server = tokio::spawn(Arc::new(HttpServer::new(McpServer::new())).serve(
+        listener,
+        async move {
+            let _ = stopped.await;
+        },
+    ));
+
+    let initialize = json!({
+        "jsonrpc": "2.0",
+        "id": 0,
+        "method": "initialize",
+        "params": {"protocolVersion": "2025-06-18", "capabilities": {}}
+    });
+    let (status, headers, body) = http_request(address, "POST", &[], &initialize).await;
+    assert_eq!(status, 200);
+    let response: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(response["result"]["protocolVersion"], "2025-06-18");
+    let session = session_id(&headers);
+    assert_eq!(session.len(), 32);
+    assert!(session.chars().all(|c| c.is_ascii_hexdigit()));
+
+    let with_session = [("Mcp-Session-Id", session.as_str())];
+    let initialized = json!({"jsonrpc": "2.0", "method": "notifications/initialized"});
+    let (status, _, _) = http_request(address, "POST", &with_session, &initialized).await;
+    assert_eq!(status, 202);
+
+    let ping = json!({"jsonrpc": "2.0", "id": 1, "method": "ping"});
+    let (status, _, body) = http_request(address, "POST", &with_session, &ping).await;
+    assert_eq!(status, 200);
+    assert_eq!(
+        serde_json::from_str::<serde_json::Value>(&body).unwrap()["id"],
+        1
+    );
+
+    // A batch is answered with one array
+    let batch = json!([
+        ping,
+        {"jsonrpc": "2.0", "method": "notifications/initialized"},
+        {"jsonrpc": "2.0", "id": 2, "method": "ping"}
+    ]);
+    let (status, _, body) = http_request(address, "POST", &with_session, &batch).await;
+    assert_eq!(status, 200);
+    let responses: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(responses[0]["id"], 1);
+    assert_eq!(responses[1]["id"], 2);
+    assert_eq!(responses.as_array().unwrap().len(), 2);
+
+    // Requests need a known session and a local origin
+    assert_eq!(http_request(address, "POST", &[], &ping).await.0, 400);
+    let unknown = [("Mcp-Session-Id", "nope")];
+    assert_eq!(http_request(address, "POST", &unknown, &ping).await.0, 404);
+    let foreign = [
+        ("Mcp-Session-Id", session.as_str()),
+        ("Origin", "http://example.com"),
+    ];
+    assert_eq!(http_request(address, "POST", &foreign, &ping).await.0, 403);
+
+    assert_eq!(
+        http_request(address, "DELETE", &with_session, &json!(null))
+            .await
+            .0,
+        204
+    );
+    assert_eq!(
+        http_request(address, "POST", &with_session, &ping).await.0,
+        404
+    );
+
+    stop.send(()).unwrap();
+    server.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_http_idle_sessions_expire() {
+    use angreal_mcp::http::HttpServer;
+    use angreal_mcp::mcp::McpServer;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let timeout = Duration::from_secs(1);
+    let http = HttpServer::new(McpServer::new()).with_idle_timeout(timeout);
+    let server = tokio::spawn(Arc::new(http).serve(listener, async move {
+        let _ = stopped.await;
+    }));
+
+    let [initialize, _] = handshake();
+    let (_, headers, _) = http_request(address, "POST", &[], &initialize).await;
+    let session = session_id(&headers);
+    let with_session = [("Mcp-Session-Id", session.as_str())];
+    let ping = json!({"jsonrpc": "2.0", "id": 1, "method": "ping"});
+
+    // Requests well inside the timeout keep the session alive past it
+    for _ in 0..12 {
+        tokio::time::sleep(timeout / 10).await;
+        assert_eq!(
+            http_request(address, "POST", &with_session, &ping).await.0,
+            200
+        );
+    }
+
+    // Each ping refreshes the session, so wait out the timeout and the idle
+    // check's period between them; give up once the deadline passes
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(15);
+    loop {
+        tokio::time::sleep(timeout * 2).await;
+        match http_request(address, "POST", &with_session, &ping).await.0 {
+            404 => break,
+            200 => assert!(
+                tokio::time::Instant::now() < deadline,
+                "session never expired"
+            ),
+            code => panic!("unexpected status {code}"),
+        }
+    }
+
+    stop.send(()).unwrap();
+    server.await.unwrap().unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_http_progress_routing() {
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+
+    let dir = tempfile::tempdir().unwrap();
+    stub_angreal(dir.path());
+    let mut child = server_command(Some(dir.path()))
+        .args(["--listen", "127.0.0.1:0"])
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stderr = tokio::io::BufReader::new(child.stderr.take().unwrap());
+    let mut banner = String::new();
+    stderr.read_line(&mut banner).await.unwrap();
+    let address: std::net::SocketAddr = banner
+        .split("http://")
+        .nth(1)
+        .and_then(|rest| rest.split('/').next())
+        .unwrap()
+        .parse()
+        .unwrap();
+
+    let [initialize, initialized] = handshake();
+    let (_, headers, _) = http_request(address, "POST", &[], &initialize).await;
+    let session = session_id(&headers);
+    let with_session = [("Mcp-Session-Id", session.as_str())];
+    http_request(address, "POST", &with_session, &initialized).await;
+
+    let run = json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": "tools/call",
+        "params": {
+            "name": "angreal_run",
+            "arguments": {"command": "count"},
+            "_meta": {"progressToken": "count"}
+        }
+    });
+    let streaming = [
+        ("Mcp-Session-Id", session.as_str()),
+        ("Accept", "text/event-stream"),
+    ];
+    let (status, _, body) = http_request(address, "POST", &streaming, &run).await;
+    assert_eq!(status, 200);
+
+    // Every line arrives as progress, and the response ends the stream
+    let messages: Vec<serde_json::Value> = body
+        .lines()
+        .filter_map(|line| line.strip_prefix("data: "))
+        .map(|data| serde_json::from_str(data).unwrap())
+        .collect();
+    let (response, progress) = messages.split_last().unwrap();
+    assert_eq!(response["id"], 1);
+    let lines: Vec<&str> = progress
+        .iter()
+        .map(|message| message["params"]["message"].as_str().unwrap())
+        .collect();
+    let expected: Vec<String> = (1..=100).map(|i| format!("line {}", i)).collect();
+    assert_eq!(lines, expected);
+
+    // Batched requests report progress on the GET stream, which is open
+    // once its headers arrive and ends when the session does
+    let mut events = tokio::net::TcpStream::connect(address).await.unwrap();
+    events
+        .write_all(
+            format!(
+                "GET /mcp HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nAccept: text/event-stream\r\nMcp-Session-Id: {}\r\n\r\n",
+                address, session
+            )
+            .as_bytes(),
+        )
+        .await
+        .unwrap();
+    let mut events = tokio::io::BufReader::new(events);
+    let mut header = String::new();
+    while header != "\r\n" {
+        header.clear();
+        events.read_line(&mut header).await.unwrap();
+    }
+
+    let (status, _, body) = http_request(address, "POST", &with_session, &json!([run])).await;
+    assert_eq!(status, 200);
+    assert_eq!(
+        serde_json::from_str::<serde_json::Value>(&body).unwrap()[0]["id"],
+        1
+    );
+    http_request(address, "DELETE", &with_session, &json!(null)).await;
+
+    let mut stream = String::new();
+    events.read_to_string(&mut stream).await.unwrap();
+    let lines: Vec<String> = stream
+        .lines()
+        .filter_map(|line| line.strip_prefix("data: "))
+        .map(|data| {
+            let message: serde_json::Value = serde_json::from_str(data).unwrap();
+            message["params"]["message"].as_str().unwrap().to_string()
+        })
+        .collect();
+    assert_eq!(lines, expected);
+}
+
+#[test]
+fn test_local_origins() {
+    use angreal_mcp::http::is_local_origin;
+
+    assert!(is_local_origin("http://localhost:3000"));
+    assert!(is_local_origin("http://127.0.0.1"));
+    assert!(is_local_origin("https://[::1]:8443"));
+    assert!(!is_local_origin("http://localhost.example.com"));
+    assert!(!is_local_origin("http://example.com"));
+    assert!(!is_local_origin("null"));
+}
+
+// The session id assigned in the raw headers of an `initialize` response
+fn session_id(headers: &str) -> String {
+    headers
+        .lines()
+        .find_map(|line| {
+            line.to_lowercase()
+                .strip_prefix("mcp-session-id: ")
+                .map(str::to_string)
+        })
+        .expect("initialize assigns a session")
+}
+
+// Sends a single HTTP/1.1 request with a JSON body and returns the status,
+// raw headers and body of the response
+async fn http_request(
+    address: std::net::SocketAddr,
+    method: &str,
+    headers: &[(&str, &str)],
+    body: &serde_json::Value,
+) -> (u16, String, String) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let body = if body.is_null() {
+        String::new()
+    } else {
+        body.to_string()
+    };
+    let mut request = format!(
+        "{} /mcp HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Type: application/json\r\nAccept: application/json\r\nContent-Length: {}\r\n",
+        method,
+        address,
+        body.len()
+    );
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str("\r\n");
+    request.push_str(&body);
+
+    let mut stream = tokio::net::TcpStream::connect(address).await.unwrap();
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+    (status, head.to_string(), body.to_string())
+}
+
+// Runs the server binary, as in [`spawn_server`], feeds it `messages` as
+// newline-delimited JSON and collects every line it writes before exiting
+// on EOF
+async fn run_server_session(
+    bin: Option<&std::path::Path>,
+    messages: &[serde_json::Value],
+) -> Vec<serde_json::Value> {
+    use tokio::io::AsyncWriteExt;
+
+    let mut child = spawn_server(bin);
+    let mut stdin = child.stdin.take().unwrap();
+    for message in messages {
+        stdin
+            .write_all(format!("{}\n", message).as_bytes())
+            .await
+            .unwrap();
+    }
+    drop(stdin);
+
+    let output = child.wait_with_output().await.unwrap();
+    String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+/// Starts the server binary with piped stdin and stdout. Given a directory,
+/// it runs there with the directory first on its `PATH`, so an `angreal`
+/// written there by [`stub_angreal`] stands in for the real one.
+fn spawn_server(bin: Option<&std::path::Path>) -> tokio::process::Child {
+    use std::process::Stdio;
+
+    let mut command = server_command(bin);
+    command.stdin(Stdio::piped()).stdout(Stdio::piped());
+    command.spawn().unwrap()
+}
+
+/// The server binary, set up as in [`spawn_server`].
+fn server_command(bin: Option<&std::path::Path>) -> tokio::process::Command {
+    let mut command = tokio::process::Command::new(env!("CARGO_BIN_EXE_angreal_mcp"));
+    command.kill_on_drop(true);
+    if let Some(bin) = bin {
+        let path = std::env::var_os("PATH").unwrap_or_default();
+        let mut paths = vec![bin.to_path_buf()];
+        paths.extend(std::env::split_paths(&path));
+        command
+            .current_dir(bin)
+            .env("PATH", std::env::join_paths(paths).unwrap());
+    }
+    command
+}
+
+/// Writes a stand-in `angreal` script into `dir`. `angreal hang <file>`
+/// prints a line, then sleeps in a child process whose pid it writes to
+/// `<file>`; `angreal tty` reports whether it is on a terminal and echoes
+/// its input; `angreal count` prints 100 numbered lines. Everything else
+/// fails, including `tree`, so argument checks
+/// are skipped.
+#[cfg(unix)]
+fn stub_angreal(dir: &std::path::Path) {
+    use std::os::unix::fs::PermissionsExt;
+
+    let script = dir.join("angreal");
+    std::fs::write(
+        &script,
+        r#"#!/bin/sh
+case "$1" in
+hang)
+    echo "partial output"
+    sleep 30 &
+    echo $! > "$2"
+    wait
+    ;;
+tty)
+    [ -t 0 ] && [ -t 1 ] && echo "stdin and stdout are a tty"
+    read -r line
+    echo "read: $line"
+    cat
+    echo "eof"
+    ;;
+count)
+    i=1
+    while [ $i -le 100 ]; do
+        echo "line $i"
+        i=$((i + 1))
+    done
+    ;;
+*)
+    exit 1
+    ;;
+esac
+"#,
+    )
+    .unwrap();
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+/// Waits for `angreal hang` from [`stub_angreal`] to write its pid file and
+/// returns the pid.
+#[cfg(unix)]
+async fn wait_for_pid(file: &std::path::Path) -> String {
+    for _ in 0..100 {
+        if let Ok(pid) = std::fs::read_to_string(file) {
+            if pid.ends_with('\n') {
+                return pid;
+            }
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    panic!("task never started");
+}
+
+/// Whether the process `pid` has gone within a few seconds. A signalled
+/// process takes a moment to exit and be reaped.
+#[cfg(unix)]
+fn process_ends(pid: &str) -> bool {
+    for _ in 0..50 {
+        let alive = std::process::Command::new("sh")
+            .args(["-c", &format!("kill -0 {} 2>/dev/null", pid.trim())])
+            .status()
+            .unwrap()
+            .success();
+        if !alive {
+            return true;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    false
+}
+
+/// The `initialize` request and `initialized` notification that open a
+/// session.
+fn handshake() -> [serde_json::Value; 2] {
+    [
+        json!({
+            "jsonrpc": "2.0",
+            "id": 0,
+            "method": "initialize",
+            "params": {"protocolVersion": "2025-06-18", "capabilities": {}}
+        }),
+        json!({"jsonrpc": "2.0", "method": "notifications/initialized"}),
+    ]
+}
+
 // Helper function to simulate request handling without running the full server
 async fn handle_request_string(request_str: String) -> String {
     use angreal_mcp::mcp::{JsonRpcRequest, McpServer};
@@ -85,21 +1556,35 @@ async fn handle_request_string(request_str: String) -> String {
     let server = McpServer::new();
 
     match serde_json::from_str::<JsonRpcRequest>(&request_str) {
-        Ok(request) => match server.handle_request(request).await {
-            Ok(response) => serde_json::to_string(&response).unwrap(),
-            Err(e) => json!({
-                "jsonrpc": "2.0",
-                "id": null,
-                "error": {
-                    "code": -32603,
-                    "message": "Internal error",
-                    "data": {
-                        "details": e.to_string()
+        Ok(request) => {
+            // Complete the handshake first unless that is what's under test
+            if request.method != "initialize" {
+                let initialize = serde_json::from_value(json!({
+                    "jsonrpc": "2.0",
+                    "id": 0,
+                    "method": "initialize",
+                    "params": {"protocolVersion": "2024-11-05", "capabilities": {}}
+                }))
+                .unwrap();
+                server.handle_request(initialize).await.unwrap();
+            }
+
+            match server.handle_request(request).await {
+                Ok(response) => serde_json::to_string(&response).unwrap(),
+                Err(e) => json!({
+                    "jsonrpc": "2.0",
+                    "id": null,
+                    "error": {
+                        "code": -32603,
+                        "message": "Internal error",
+                        "data": {
+                            "details": e.to_string()
+                        }
                     }
-                }
-            })
-            .to_string(),
-        },
+                })
+                .to_string(),
+            }
+        }
         Err(_) => json!({
             "jsonrpc": "2.0",
             "id": null,
@@ -111,3 +1596,410 @@ async fn handle_request_string(request_str: String) -> String {
         .to_string(),
     }
 }
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_unix_socket_sessions() {
+    use angreal_mcp::mcp::McpServer;
+    use angreal_mcp::socket;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::UnixStream;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("mcp.sock");
+
+    // A socket file nobody listens on any more is replaced
+    drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+    let listener = socket::bind(&path).await.unwrap();
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let server = tokio::spawn(socket::serve(McpServer::new(), listener, async move {
+        let _ = stopped.await;
+    }));
+
+    // ...but one that still accepts connections is not
+    assert!(socket::bind(&path).await.is_err());
+
+    async fn request(stream: &mut BufReader<UnixStream>, message: serde_json::Value) -> String {
+        let mut line = message.to_string();
+        line.push('\n');
+        stream.get_mut().write_all(line.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_line(&mut response).await.unwrap();
+        response
+    }
+
+    let initialize = json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": "initialize",
+        "params": {"protocolVersion": "2025-06-18", "capabilities": {}}
+    });
+    let mut first = BufReader::new(UnixStream::connect(&path).await.unwrap());
+    let mut second = BufReader::new(UnixStream::connect(&path).await.unwrap());
+    for client in [&mut first, &mut second] {
+        let response: serde_json::Value =
+            serde_json::from_str(&request(client, initialize.clone()).await).unwrap();
+        assert_eq!(response["result"]["protocolVersion"], "2025-06-18");
+    }
+
+    // Each connection is a session of its own, so one going away leaves the
+    // other working
+    drop(second);
+    let ping = json!({"jsonrpc": "2.0", "id": 2, "method": "ping"});
+    let response: serde_json::Value =
+        serde_json::from_str(&request(&mut first, ping).await).unwrap();
+    assert_eq!(response["result"], json!({}));
+
+    // Shutting down closes the remaining connections
+    stop.send(()).unwrap();
+    server.await.unwrap().unwrap();
+    let mut rest = String::new();
+    first.read_line(&mut rest).await.unwrap();
+    assert!(rest.is_empty());
+}
+
+#[tokio::test]
+async fn test_batch_requests() {
+    use angreal_mcp::mcp::McpServer;
+    use angreal_mcp::transport::{duplex, serve, Transport};
+
+    let (server_end, mut client) = duplex();
+    let session = tokio::spawn(serve(McpServer::new(), server_end, std::future::pending()));
+
+    async fn exchange(
+        client: &mut impl Transport,
+        message: serde_json::Value,
+    ) -> serde_json::Value {
+        client.send(message.to_string()).await.unwrap();
+        serde_json::from_str(&client.receive().await.unwrap().unwrap()).unwrap()
+    }
+
+    // Requests later in a batch see the handshake earlier in it, and the
+    // responses come back in request order without the notifications
+    let responses = exchange(
+        &mut client,
+        json!([
+            {
+                "jsonrpc": "2.0",
+                "id": "init",
+                "method": "initialize",
+                "params": {"protocolVersion": "2025-06-18", "capabilities": {}}
+            },
+            {"jsonrpc": "2.0", "method": "notifications/initialized"},
+            {"jsonrpc": "2.0", "id": 1, "method": "tools/list"},
+            1,
+            {"jsonrpc": "2.0", "id": 2},
+            {"jsonrpc": "2.0", "id": 3, "method": "ping"}
+        ]),
+    )
+    .await;
+    let responses = responses.as_array().unwrap();
+    assert_eq!(responses.len(), 5);
+    assert_eq!(responses[0]["id"], "init");
+    assert!(responses[0]["result"]["protocolVersion"].is_string());
+    assert_eq!(responses[1]["id"], 1);
+    assert!(responses[1]["result"]["tools"].is_array());
+    assert!(responses[2]["id"].is_null());
+    assert_eq!(responses[2]["error"]["code"], -32600);
+    assert_eq!(responses[3]["id"], 2);
+    assert_eq!(responses[3]["error"]["code"], -32600);
+    assert_eq!(responses[4]["id"], 3);
+
+    // An empty batch gets a single error, not an array
+    let response = exchange(&mut client, json!([])).await;
+    assert_eq!(response["error"]["code"], -32600);
+
+    // A batch of notifications gets no answer at all
+    client
+        .send(json!([{"jsonrpc": "2.0", "method": "notifications/initialized"}]).to_string())
+        .await
+        .unwrap();
+    let response = exchange(
+        &mut client,
+        json!({"jsonrpc": "2.0", "id": 4, "method": "ping"}),
+    )
+    .await;
+    assert_eq!(response["id"], 4);
+
+    client.close().await.unwrap();
+    session.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_error_codes_and_ids() {
+    use angreal_mcp::mcp::McpServer;
+    use angreal_mcp::transport::{duplex, serve, Transport};
+
+    let (server_end, mut client) = duplex();
+    let session = tokio::spawn(serve(McpServer::new(), server_end, std::future::pending()));
+
+    async fn exchange(client: &mut impl Transport, message: String) -> serde_json::Value {
+        client.send(message).await.unwrap();
+        serde_json::from_str(&client.receive().await.unwrap().unwrap()).unwrap()
+    }
+    let call = |id: u64, params: serde_json::Value| {
+        json!({"jsonrpc": "2.0", "id": id, "method": "tools/call", "params": params}).to_string()
+    };
+
+    let initialize = json!({
+        "jsonrpc": "2.0",
+        "id": 0,
+        "method": "initialize",
+        "params": {"protocolVersion": "2025-06-18", "capabilities": {}}
+    });
+    exchange(&mut client, initialize.to_string()).await;
+    client
+        .send(json!({"jsonrpc": "2.0", "method": "notifications/initialized"}).to_string())
+        .await
+        .unwrap();
+
+    // Malformed JSON has no id to recover
+    let response = exchange(&mut client, "{\"id\": 1,".to_string()).await;
+    assert_eq!(response["error"]["code"], -32700);
+    assert!(response["id"].is_null());
+
+    // Valid JSON that isn't a request keeps its id
+    let response = exchange(&mut client, json!({"jsonrpc": "2.0", "id": 1}).to_string()).await;
+    assert_eq!(response["error"]["code"], -32600);
+    assert_eq!(response["id"], 1);
+    let wrong_version = json!({"jsonrpc": "1.0", "id": "a", "method": "ping"});
+    let response = exchange(&mut client, wrong_version.to_string()).await;
+    assert_eq!(response["error"]["code"], -32600);
+    assert_eq!(response["id"], "a");
+
+    // Bad tool call params point at the field at fault
+    let cases = [
+        (json!(null), ""),
+        (json!({"name": 5}), "/name"),
+        (
+            json!({"name": "angreal_run", "arguments": []}),
+            "/arguments",
+        ),
+        (
+            json!({"name": "angreal_run", "arguments": {"command": "x", "timeout_seconds": "9"}}),
+            "/arguments/timeout_seconds",
+        ),
+        (
+            json!({"name": "angreal_run", "arguments": {"command": "x", "env": {"A/B": 1}}}),
+            "/arguments/env/A~1B",
+        ),
+        (
+            json!({"name": "angreal_job_kill", "arguments": {}}),
+            "/arguments/job_id",
+        ),
+        (
+            json!({"name": "angreal_run_output", "arguments": {"run_id": "run-1", "offset": "x"}}),
+            "/arguments/offset",
+        ),
+    ];
+    for (id, (params, pointer)) in (10..).zip(cases) {
+        let params = if params.is_null() {
+            json!({"jsonrpc": "2.0", "id": id, "method": "tools/call"}).to_string()
+        } else {
+            call(id, params)
+        };
+        let response = exchange(&mut client, params).await;
+        assert_eq!(response["id"], id);
+        assert_eq!(response["error"]["code"], -32602);
+        assert_eq!(response["error"]["data"]["pointer"], pointer);
+    }
+
+    // Unknown tools are unknown methods
+    let response = exchange(&mut client, call(20, json!({"name": "nope"}))).await;
+    assert_eq!(response["id"], 20);
+    assert_eq!(response["error"]["code"], -32601);
+
+    // Responses from the client are ignored rather than answered
+    client
+        .send(json!({"jsonrpc": "2.0", "id": 5, "result": {}}).to_string())
+        .await
+        .unwrap();
+    let ping = json!({"jsonrpc": "2.0", "id": 21, "method": "ping"});
+    assert_eq!(exchange(&mut client, ping.to_string()).await["id"], 21);
+
+    client.close().await.unwrap();
+    session.await.unwrap().unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_run_timeout_kills_task() {
+    let dir = tempfile::tempdir().unwrap();
+    stub_angreal(dir.path());
+    let pid_file = dir.path().join("pid");
+
+    let [initialize, initialized] = handshake();
+    let run = json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": "tools/call",
+        "params": {
+            "name": "angreal_run",
+            "arguments": {
+                "command": "hang",
+                "args": [pid_file],
+                "timeout_seconds": 1
+            }
+        }
+    });
+    let responses = run_server_session(Some(dir.path()), &[initialize, initialized, run]).await;
+    let result = &responses.iter().find(|r| r["id"] == 1).unwrap()["result"];
+    assert_eq!(result["isError"], true);
+    assert_eq!(result["structuredContent"]["timed_out"], true);
+    assert!(result["structuredContent"]["stdout"]
+        .as_str()
+        .unwrap()
+        .contains("partial output"));
+
+    // The whole process group went, not just the task itself
+    let pid = std::fs::read_to_string(&pid_file).unwrap();
+    assert!(process_ends(&pid));
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_run_with_pty() {
+    let dir = tempfile::tempdir().unwrap();
+    stub_angreal(dir.path());
+
+    let [initialize, initialized] = handshake();
+    let run = json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": "tools/call",
+        "params": {
+            "name": "angreal_run",
+            "arguments": {
+                "command": "tty",
+                "pty": true,
+                "stdin": "hello\nworld",
+                "timeout_seconds": 10
+            }
+        }
+    });
+    let responses = run_server_session(Some(dir.path()), &[initialize, initialized, run]).await;
+    let result = &responses.iter().find(|r| r["id"] == 1).unwrap()["result"];
+    assert!(result.get("isError").is_none(), "{}", result);
+    assert_eq!(result["structuredContent"]["timed_out"], false);
+
+    // Echo is off, so only the task's own output comes back. The unfinished
+    // last line is completed and followed by end of file.
+    let stdout = result["structuredContent"]["stdout"].as_str().unwrap();
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(
+        lines,
+        ["stdin and stdout are a tty", "read: hello", "world", "eof"],
+        "{:?}",
+        stdout
+    );
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_cancel_running_task() {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let dir = tempfile::tempdir().unwrap();
+    stub_angreal(dir.path());
+    let pid_file = dir.path().join("pid");
+
+    let mut child = spawn_server(Some(dir.path()));
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = BufReader::new(child.stdout.take().unwrap()).lines();
+    let run = json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": "tools/call",
+        "params": {
+            "name": "angreal_run",
+            "arguments": {"command": "hang", "args": [pid_file]}
+        }
+    });
+    for message in handshake().into_iter().chain([run]) {
+        stdin
+            .write_all(format!("{}\n", message).as_bytes())
+            .await
+            .unwrap();
+    }
+
+    // Cancel once the task is actually running
+    let pid = wait_for_pid(&pid_file).await;
+    let cancel = json!({
+        "jsonrpc": "2.0",
+        "method": "notifications/cancelled",
+        "params": {"requestId": 1}
+    });
+    let ping = json!({"jsonrpc": "2.0", "id": 2, "method": "ping"});
+    for message in [cancel, ping] {
+        stdin
+            .write_all(format!("{}\n", message).as_bytes())
+            .await
+            .unwrap();
+    }
+    drop(stdin);
+
+    let mut ids = Vec::new();
+    while let Some(line) = stdout.next_line().await.unwrap() {
+        let response: serde_json::Value = serde_json::from_str(&line).unwrap();
+        ids.push(response["id"].clone());
+    }
+    child.wait().await.unwrap();
+
+    // The cancelled request is never answered, and its task is killed
+    assert_eq!(ids, vec![json!(0), json!(2)]);
+    assert!(process_ends(&pid));
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_job_start_reports_pid() {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let dir = tempfile::tempdir().unwrap();
+    stub_angreal(dir.path());
+    let pid_file = dir.path().join("pid");
+
+    let mut child = spawn_server(Some(dir.path()));
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = BufReader::new(child.stdout.take().unwrap()).lines();
+    let call = |id: u64, name: &str, arguments: serde_json::Value| {
+        json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": "tools/call",
+            "params": {"name": name, "arguments": arguments}
+        })
+    };
+    let start = call(
+        1,
+        "angreal_job_start",
+        json!({"command": "hang", "args": [pid_file]}),
+    );
+    let kill = call(2, "angreal_job_kill", json!({"job_id": "job-1"}));
+
+    let status = |line: String| -> serde_json::Value {
+        let response: serde_json::Value = serde_json::from_str(&line).unwrap();
+        serde_json::from_str(response["result"]["content"][0]["text"].as_str().unwrap()).unwrap()
+    };
+    for message in handshake().into_iter().chain([start]) {
+        stdin
+            .write_all(format!("{}\n", message).as_bytes())
+            .await
+            .unwrap();
+    }
+    stdout.next_line().await.unwrap();
+    let started = status(stdout.next_line().await.unwrap().unwrap());
+    assert_eq!(started["status"], "running");
+    assert!(started["pid"].is_u64());
+
+    let pid = wait_for_pid(&pid_file).await;
+    stdin
+        .write_all(format!("{}\n", kill).as_bytes())
+        .await
+        .unwrap();
+    drop(stdin);
+    let killed = status(stdout.next_line().await.unwrap().unwrap());
+    assert_eq!(killed["status"], "killed");
+    child.wait().await.unwrap();
+    assert!(process_ends(&pid));
+}